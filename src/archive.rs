@@ -0,0 +1,188 @@
+use crc32;
+use inflate;
+use std::fmt;
+
+//
+// Compressed ROM archives (zip, gzip)
+//
+
+const ZIP_LOCAL_HEADER_SIG: u32 = 0x04034b50;
+const ZIP_CENTRAL_HEADER_SIG: u32 = 0x02014b50;
+const ZIP_END_OF_DIR_SIG: u32 = 0x06054b50;
+const ZIP_END_OF_DIR_SIZE: uint = 22;
+const ZIP_LOCAL_HEADER_SIZE: uint = 30;
+const ZIP_CENTRAL_HEADER_SIZE: uint = 46;
+
+const ZIP_METHOD_STORED: u16 = 0;
+const ZIP_METHOD_DEFLATE: u16 = 8;
+
+const GZIP_METHOD_DEFLATE: u8 = 8;
+const GZIP_FLAG_HCRC: u8 = 0b0000_0010;
+const GZIP_FLAG_EXTRA: u8 = 0b0000_0100;
+const GZIP_FLAG_NAME: u8 = 0b0000_1000;
+const GZIP_FLAG_COMMENT: u8 = 0b0001_0000;
+
+const ROM_EXTENSIONS: &'static [&'static str] = &[".gb", ".gbc"];
+
+// Largest ROM size given by a cartridge header (8 MiB), limiting how much a
+// crafted archive can make us decompress
+const MAX_ROM_SIZE: uint = 0x800000;
+
+pub enum ArchiveError {
+  CorruptArchive(&'static str),
+  NoRomInArchive,
+  UnsupportedCompression(u16),
+  ChecksumMismatch,
+}
+
+impl fmt::Show for ArchiveError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      CorruptArchive(msg)          => write!(f, "corrupt archive: {:s}", msg),
+      NoRomInArchive               => write!(f, "no .gb or .gbc file in archive"),
+      UnsupportedCompression(m)    => write!(f, "unsupported compression method {:u}", m),
+      ChecksumMismatch             => write!(f, "CRC32 mismatch in decompressed data"),
+    }
+  }
+}
+
+pub type ArchiveResult<T> = Result<T, ArchiveError>;
+
+pub fn is_zip(data: &[u8]) -> bool {
+  data.len() >= 4 && le32(data, 0) == ZIP_LOCAL_HEADER_SIG
+}
+
+pub fn is_gzip(data: &[u8]) -> bool {
+  data.len() >= 2 && data[0] == 0x1f && data[1] == 0x8b
+}
+
+// Returns the contents of the first ROM in a zip or gzip archive, or None if
+// the data is not compressed.
+pub fn extract_rom(data: &[u8]) -> ArchiveResult<Option<Vec<u8>>> {
+  if is_zip(data) {
+    extract_zip(data).map(|rom| Some(rom))
+  } else if is_gzip(data) {
+    extract_gzip(data).map(|rom| Some(rom))
+  } else {
+    Ok(None)
+  }
+}
+
+fn le16(data: &[u8], pos: uint) -> u16 {
+  data[pos] as u16 | (data[pos + 1] as u16 << 8)
+}
+
+fn le32(data: &[u8], pos: uint) -> u32 {
+  le16(data, pos) as u32 | (le16(data, pos + 2) as u32 << 16)
+}
+
+fn check(cond: bool, msg: &'static str) -> ArchiveResult<()> {
+  if cond { Ok(()) } else { Err(CorruptArchive(msg)) }
+}
+
+fn is_rom_name(name: &[u8]) -> bool {
+  let name = name.iter()
+                 .map(|&b| if b >= b'A' && b <= b'Z' { b + 0x20 } else { b })
+                 .collect::<Vec<u8>>();
+  ROM_EXTENSIONS.iter().any(|ext| name.as_slice().ends_with(ext.as_bytes()))
+}
+
+fn extract_zip(data: &[u8]) -> ArchiveResult<Vec<u8>> {
+  // The end of central directory record is followed by a comment of up to 64 KiB
+  try!(check(data.len() >= ZIP_END_OF_DIR_SIZE, "missing end of central directory"));
+  let mut end = data.len() - ZIP_END_OF_DIR_SIZE;
+  while le32(data, end) != ZIP_END_OF_DIR_SIG {
+    try!(check(end > 0 && data.len() - end < 0x10000 + ZIP_END_OF_DIR_SIZE,
+               "missing end of central directory"));
+    end -= 1;
+  }
+
+  let entries = le16(data, end + 10) as uint;
+  let mut pos = le32(data, end + 16) as uint;
+
+  // Sizes are taken from the central directory, since local headers may
+  // defer them to a data descriptor
+  for _ in range(0, entries) {
+    try!(check(pos + ZIP_CENTRAL_HEADER_SIZE <= data.len() &&
+               le32(data, pos) == ZIP_CENTRAL_HEADER_SIG,
+               "invalid central directory entry"));
+    let method = le16(data, pos + 10);
+    let crc = le32(data, pos + 16);
+    let compressed_size = le32(data, pos + 20) as uint;
+    let size = le32(data, pos + 24) as uint;
+    let name_len = le16(data, pos + 28) as uint;
+    let extra_len = le16(data, pos + 30) as uint;
+    let comment_len = le16(data, pos + 32) as uint;
+    let local_offset = le32(data, pos + 42) as uint;
+
+    let name_start = pos + ZIP_CENTRAL_HEADER_SIZE;
+    try!(check(name_start + name_len <= data.len(), "truncated file name"));
+    let name = data.slice(name_start, name_start + name_len);
+    pos = name_start + name_len + extra_len + comment_len;
+
+    if !is_rom_name(name) {
+      continue;
+    }
+
+    try!(check(local_offset + ZIP_LOCAL_HEADER_SIZE <= data.len() &&
+               le32(data, local_offset) == ZIP_LOCAL_HEADER_SIG,
+               "invalid local file header"));
+    let start = local_offset + ZIP_LOCAL_HEADER_SIZE +
+                le16(data, local_offset + 26) as uint +
+                le16(data, local_offset + 28) as uint;
+    try!(check(start + compressed_size <= data.len(), "truncated file data"));
+    let compressed = data.slice(start, start + compressed_size);
+    try!(check(size <= MAX_ROM_SIZE, "file too large for a ROM"));
+
+    let rom =
+      match method {
+        ZIP_METHOD_STORED  => compressed.to_vec(),
+        ZIP_METHOD_DEFLATE => try!(inflate::inflate(compressed, size).map_err(CorruptArchive)),
+        _ => return Err(UnsupportedCompression(method)),
+      };
+    if rom.len() != size || crc32::checksum(rom.as_slice()) != crc {
+      return Err(ChecksumMismatch);
+    }
+    return Ok(rom);
+  }
+
+  Err(NoRomInArchive)
+}
+
+fn extract_gzip(data: &[u8]) -> ArchiveResult<Vec<u8>> {
+  try!(check(data.len() >= 18, "truncated gzip header"));
+  if data[2] != GZIP_METHOD_DEFLATE {
+    return Err(UnsupportedCompression(data[2] as u16));
+  }
+
+  let flags = data[3];
+  let mut pos = 10u;
+  if (flags & GZIP_FLAG_EXTRA) != 0 {
+    try!(check(pos + 2 <= data.len(), "truncated gzip header"));
+    pos += 2 + le16(data, pos) as uint;
+  }
+  for &flag in [GZIP_FLAG_NAME, GZIP_FLAG_COMMENT].iter() {
+    if (flags & flag) != 0 {
+      // Zero-terminated string
+      while pos < data.len() && data[pos] != 0 {
+        pos += 1;
+      }
+      pos += 1;
+    }
+  }
+  if (flags & GZIP_FLAG_HCRC) != 0 {
+    pos += 2;
+  }
+  try!(check(pos + 8 <= data.len(), "truncated gzip header"));
+
+  // The trailer holds the CRC32 and size (mod 2^32) of the original data
+  let trailer = data.len() - 8;
+  let size = le32(data, trailer + 4) as uint;
+  try!(check(size <= MAX_ROM_SIZE, "file too large for a ROM"));
+  let rom = try!(inflate::inflate(data.slice(pos, trailer), size).map_err(CorruptArchive));
+  if crc32::checksum(rom.as_slice()) != le32(data, trailer) ||
+     rom.len() as u32 != le32(data, trailer + 4) {
+    return Err(ChecksumMismatch);
+  }
+  Ok(rom)
+}
@@ -0,0 +1,176 @@
+use cpu;
+use sdl2;
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use std::collections::RingBuf;
+use std::sync::{Arc, Mutex};
+
+//
+// Statics
+//
+
+pub const SAMPLE_RATE: uint = 44100;
+const CHANNELS: uint = 2;
+const DEVICE_SAMPLES: u16 = 1024; // Samples per channel per callback
+
+// The ring buffer holds this many frames; emulation aims to keep it half full
+const BUFFER_FRAMES: uint = 8192;
+
+// Maximum relative deviation of the resampling rate used to keep the buffer
+// level centered (dynamic rate control)
+const MAX_RATE_DELTA: f64 = 0.005;
+
+
+//
+// Resampler
+//
+
+// Downsamples the sound unit's output, which changes at most once per CPU
+// step, to an output rate by averaging over each output sample period.
+pub struct Resampler {
+  cycles_per_sample: f64,
+  acc_cycles: f64,
+  acc_left: f64,
+  acc_right: f64,
+  samples: Vec<i16>, // Interleaved stereo samples not yet taken
+}
+
+impl Resampler {
+  pub fn new(sample_rate: uint) -> Resampler {
+    Resampler {
+      cycles_per_sample: cpu::CYCLES_PER_SEC as f64 / sample_rate as f64,
+      acc_cycles: 0.0,
+      acc_left: 0.0,
+      acc_right: 0.0,
+      samples: vec!(),
+    }
+  }
+
+  pub fn set_rate(&mut self, sample_rate: f64) {
+    self.cycles_per_sample = cpu::CYCLES_PER_SEC as f64 / sample_rate;
+  }
+
+  pub fn push(&mut self, output: (i16, i16), cycles: u8) {
+    let (left, right) = output;
+    let mut remaining = cycles as f64;
+
+    while self.acc_cycles + remaining >= self.cycles_per_sample {
+      // Complete the current output sample
+      let part = self.cycles_per_sample - self.acc_cycles;
+      self.acc_left += left as f64 * part;
+      self.acc_right += right as f64 * part;
+      self.samples.push((self.acc_left / self.cycles_per_sample) as i16);
+      self.samples.push((self.acc_right / self.cycles_per_sample) as i16);
+
+      remaining -= part;
+      self.acc_cycles = 0.0;
+      self.acc_left = 0.0;
+      self.acc_right = 0.0;
+    }
+
+    self.acc_cycles += remaining;
+    self.acc_left += left as f64 * remaining;
+    self.acc_right += right as f64 * remaining;
+  }
+
+  // Completes a partly accumulated sample, e.g. at the end of a recording
+  pub fn finish(&mut self) {
+    if self.acc_cycles > 0.0 {
+      self.samples.push((self.acc_left / self.acc_cycles) as i16);
+      self.samples.push((self.acc_right / self.acc_cycles) as i16);
+      self.acc_cycles = 0.0;
+      self.acc_left = 0.0;
+      self.acc_right = 0.0;
+    }
+  }
+
+  pub fn take_samples(&mut self) -> Vec<i16> {
+    let mut samples = Vec::with_capacity(self.samples.len());
+    ::std::mem::swap(&mut samples, &mut self.samples);
+    samples
+  }
+}
+
+
+//
+// Audio Output
+//
+
+struct Playback {
+  buffer: Arc<Mutex<RingBuf<i16>>>,
+  last: [i16, ..2],
+}
+
+impl AudioCallback<i16> for Playback {
+  fn callback(&mut self, out: &mut [i16]) {
+    let mut buffer = self.buffer.lock();
+    for (i, sample) in out.iter_mut().enumerate() {
+      match buffer.pop_front() {
+        Some(s) => { self.last[i % CHANNELS] = s; *sample = s },
+        None => *sample = self.last[i % CHANNELS], // Underrun, hold the last level
+      }
+    }
+  }
+}
+
+pub struct AudioOut {
+  device: AudioDevice<Playback>,
+  buffer: Arc<Mutex<RingBuf<i16>>>,
+  resampler: Resampler,
+}
+
+impl AudioOut {
+  pub fn new() -> Result<AudioOut, String> {
+    if !sdl2::init_subsystem(sdl2::INIT_AUDIO) {
+      return Err(sdl2::get_error());
+    }
+
+    let buffer = Arc::new(Mutex::new(RingBuf::with_capacity(BUFFER_FRAMES * CHANNELS)));
+    let desired = AudioSpecDesired {
+      freq: SAMPLE_RATE as i32,
+      channels: CHANNELS as u8,
+      samples: DEVICE_SAMPLES,
+      callback: Playback { buffer: buffer.clone(), last: [0, 0] },
+    };
+
+    let device = try!(desired.open_audio_device(None, false));
+    device.resume();
+
+    Ok(AudioOut { device: device, buffer: buffer, resampler: Resampler::new(SAMPLE_RATE) })
+  }
+
+  pub fn push(&mut self, output: (i16, i16), cycles: u8) {
+    self.resampler.push(output, cycles);
+  }
+
+  // Buffer fill level in [0, 1]
+  pub fn fill_level(&self) -> f64 {
+    self.buffer.lock().len() as f64 / (BUFFER_FRAMES * CHANNELS) as f64
+  }
+
+  // Moves resampled output to the device buffer and adjusts the resampling
+  // rate to steer the fill level towards one half.
+  pub fn flush(&mut self) {
+    let samples = self.resampler.take_samples();
+    {
+      let mut buffer = self.buffer.lock();
+      for &s in samples.iter() {
+        if buffer.len() >= BUFFER_FRAMES * CHANNELS {
+          break; // Overrun, drop the rest
+        }
+        buffer.push_back(s);
+      }
+    }
+
+    let fill = self.fill_level();
+    let ratio = 1.0 + MAX_RATE_DELTA * (1.0 - 2.0 * fill);
+    self.resampler.set_rate(SAMPLE_RATE as f64 * ratio);
+  }
+
+  // Blocks until the buffer has drained to one half, so emulation speed
+  // follows the audio clock.
+  pub fn wait(&self) {
+    while self.fill_level() > 0.5 {
+      sdl2::timer::delay(1);
+    }
+  }
+}
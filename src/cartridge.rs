@@ -1,90 +1,462 @@
+use archive;
+use crc32;
+use header;
 use mem::Mem;
+use patch;
+use rtc;
 use std::cmp;
-use std::io::{File, IoResult, SeekSet};
+use std::fmt;
+use std::io::{File, IoError, IoResult};
+use time;
 
-static HEADER_OFFSET: i64 = 0x100;
-static ROM_BANK_SIZE: uint = 0x4000;
+pub static ROM_BANK_SIZE: uint = 0x4000;
+static RAM_BANK_SIZE: uint = 0x2000;
+static MBC2_RAM_SIZE: uint = 0x200; // 512 x 4 bits
+static MULTICART_GAME_SIZE: uint = 0x40000; // 256 KiB per game in MBC1M collections
+static MULTICART_ROM_BANKS: uint = 64;
+static PATCH_EXTENSIONS: &'static [&'static str] = &["ips", "ups", "bps"];
 
 enum MBC {
-  MBC1
+  MBC1,
+  MBC2,
+  MBC3,
+  MBC5,
+}
+
+pub enum CartridgeError {
+  IoFailure(IoError),
+  TruncatedFile(uint),   // Actual file size
+  UnsupportedMapper(u8), // Cartridge type
+  BadRomSize(u8),        // ROM size code
+  BadRamSize(u8),        // RAM size code
+  NonAsciiTitle,
+  BadPatch(patch::PatchError),
+  BadArchive(archive::ArchiveError),
+}
+
+impl fmt::Show for CartridgeError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      IoFailure(ref e)         => write!(f, "I/O error: {}", e),
+      TruncatedFile(size)      => write!(f, "file is truncated ({:u} bytes)", size),
+      UnsupportedMapper(code)  => write!(f, "unsupported cartridge type 0x{:02X}", code),
+      BadRomSize(code)         => write!(f, "unsupported ROM size code 0x{:02X}", code),
+      BadRamSize(code)         => write!(f, "unsupported RAM size code 0x{:02X}", code),
+      NonAsciiTitle            => write!(f, "cartridge title is not ASCII"),
+      BadPatch(ref e)          => write!(f, "failed to apply patch: {}", e),
+      BadArchive(ref e)        => write!(f, "failed to extract ROM: {}", e),
+    }
+  }
+}
+
+// Anything that can be plugged into the cartridge slot, i.e. mapped to
+// 0x0000-0x7fff and 0xa000-0xbfff
+pub trait Slot: Mem {
+  fn tick(&mut self, cycles: u8);
+  fn save_ram(&self) -> IoResult<()>;
+}
+
+// Receives motor state changes from rumble cartridges
+pub trait RumbleHandler {
+  fn set_rumble(&mut self, on: bool);
 }
 
 pub struct Cartridge {
+  pub header: header::Header,
   pub title: String,
   pub cartridge_type: u8,
   pub rom_size: u8,
   pub ram_size: u8,
   pub rom_banks: Vec<Vec<u8>>,
-  pub rom_bank: u8,
+  pub rom_bank: u16, // Lower bits of ROM bank (register 0x2000-0x3fff)
+  pub bank_high: u8, // Upper ROM bank bits, RAM bank or RTC register (register 0x4000-0x5fff)
+  pub ram: Vec<u8>,
+  pub ram_enabled: bool,
+  pub ram_mode: bool, // ROM/RAM mode select (register 0x6000-0x7fff)
   pub mbc: Option<MBC>,
+  pub multicart: bool, // MBC1M wiring (4-bit lower ROM bank)
+  pub rtc: Option<rtc::Rtc>,
+  rumble: Option<Box<RumbleHandler + 'static>>,
+  rumble_on: bool,
+  save_path: Option<Path>, // Battery-backed RAM is persisted here
 }
 
-impl Cartridge {
-  pub fn from_path(path: &Path) -> IoResult<Cartridge> {
-    Cartridge::from_file(&mut File::open(path).unwrap())
+// Mapper for a cartridge type, or None for plain ROM (+RAM) cartridges
+fn mapper(cartridge_type: u8) -> Result<Option<MBC>, CartridgeError> {
+  match cartridge_type {
+    0x00 => Ok(None),
+    0x01...0x03 => Ok(Some(MBC1)),
+    0x05 | 0x06 => Ok(Some(MBC2)),
+    0x0f...0x13 => Ok(Some(MBC3)),
+    0x19...0x1e => Ok(Some(MBC5)),
+    _ => Err(UnsupportedMapper(cartridge_type)),
   }
+}
 
-  fn from_file(file: &mut File) -> IoResult<Cartridge> {
-    use std::str;
+pub fn is_supported_type(cartridge_type: u8) -> bool {
+  mapper(cartridge_type).is_ok()
+}
 
-    let mut header = [0, ..80];
-    try!(file.seek(HEADER_OFFSET, SeekSet));
-    try!(file.read(header));
+// Number of 16 KiB ROM banks given by a header's ROM size code
+pub fn rom_bank_count(rom_size: u8) -> Option<uint> {
+  match rom_size {
+    0...7 => Some(2 << (rom_size as uint)),
+    0x52  => Some(72),
+    0x53  => Some(80),
+    0x54  => Some(96),
+    _ => None,
+  }
+}
 
-    let title = str::from_utf8(header.slice(0x34, 0x43)).unwrap().to_string();
+// Bytes of external RAM given by a header's RAM size code
+pub fn ram_bytes(cartridge_type: u8, ram_size: u8) -> Option<uint> {
+  match ram_size {
+    0x00 if cartridge_type == 0x05 || cartridge_type == 0x06 => Some(MBC2_RAM_SIZE),
+    0x00 => Some(0),
+    0x01 => Some(0x800),   // 2 KiB (single partial bank)
+    0x02 => Some(0x2000),  // 8 KiB (1 bank)
+    0x03 => Some(0x8000),  // 32 KiB (4 banks)
+    0x04 => Some(0x20000), // 128 KiB (16 banks)
+    0x05 => Some(0x10000), // 64 KiB (8 banks)
+    _ => None,
+  }
+}
 
-    let cartridge_type = header[0x47];
-    let mbc =
-      match cartridge_type {
-        0x00 => None,
-        0x01 => Some(MBC1),
-        _ => panic!("unsupported cartridge type: 0x{:02X}", cartridge_type)
-      };
+// Reads a ROM image, extracting it from an archive and applying the given
+// patch or else one with the same stem (rom.ips, rom.ups or rom.bps) if
+// present.
+pub fn read_rom(path: &Path, patch_path: Option<&Path>) -> Result<Vec<u8>, CartridgeError> {
+  let patch_path =
+    match patch_path {
+      Some(p) => Some(p.clone()),
+      None => PATCH_EXTENSIONS.iter()
+                              .map(|ext| path.with_extension(*ext))
+                              .find(|p| p.exists()),
+    };
 
-    let rom_size = header[0x48];
-    let rom_bank_count =
-      match rom_size {
-        0...7 => 2 << (rom_size as uint),
-        0x52  => 72,
-        0x53  => 80,
-        0x54  => 96,
-        _ => panic!("unsupported ROM size: 0x{:02X}", rom_size),
-      };
-    let mut rom_banks = Vec::with_capacity(rom_bank_count);
+  let mut data = try!(File::open(path).read_to_end().map_err(IoFailure));
+
+  // Zip and gzip archives are recognized by their magic bytes
+  match try!(archive::extract_rom(data.as_slice()).map_err(BadArchive)) {
+    Some(rom) => data = rom,
+    None => (),
+  }
+
+  match patch_path {
+    Some(ref p) => {
+      let patch_data = try!(File::open(p).read_to_end().map_err(IoFailure));
+      data = try!(patch::apply(data.as_slice(), patch_data.as_slice()).map_err(BadPatch));
+      info!("Applied patch {}", p.display());
+    },
+    None => (),
+  }
+  Ok(data)
+}
+
+impl Cartridge {
+  // Loads a ROM (see read_rom) and its battery-backed RAM from rom.sav
+  pub fn from_path(path: &Path, patch_path: Option<&Path>) -> Result<Cartridge, CartridgeError> {
+    Cartridge::with_save_path(path, patch_path, &path.with_extension("sav"))
+  }
+
+  // Like from_path, but keeps battery-backed RAM in the given file, e.g. for
+  // a second instance of the same ROM
+  pub fn with_save_path(path: &Path, patch_path: Option<&Path>, save_path: &Path)
+                        -> Result<Cartridge, CartridgeError> {
+    let data = try!(read_rom(path, patch_path));
+    let mut cart = try!(Cartridge::from_bytes(data.as_slice()));
+    if cart.has_battery() {
+      cart.save_path = Some(save_path.clone());
+      try!(cart.load_ram().map_err(IoFailure));
+    }
+    Ok(cart)
+  }
+
+  pub fn from_bytes(data: &[u8]) -> Result<Cartridge, CartridgeError> {
+    if data.len() < header::HEADER_END {
+      return Err(TruncatedFile(data.len()));
+    }
+    let header = header::Header::parse(data);
+
+    if !header.title.iter().all(|&b| b < 0x80) {
+      return Err(NonAsciiTitle);
+    }
+    let title = header.title.iter()
+                           .take_while(|&&b| b != 0)
+                           .map(|&b| b as char)
+                           .collect::<String>();
 
-    try!(file.seek(0, SeekSet));
+    let cartridge_type = header.cartridge_type;
+    let mbc = try!(mapper(cartridge_type));
 
-    for _ in range(0, rom_bank_count) {
-      let mut bank = Vec::with_capacity(ROM_BANK_SIZE);
-      try!(file.push(ROM_BANK_SIZE, &mut bank));
-      rom_banks.push(bank);
+    let rom_size = header.rom_size;
+    let rom_bank_count = match rom_bank_count(rom_size) {
+      Some(count) => count,
+      None => return Err(BadRomSize(rom_size)),
+    };
+    if data.len() < rom_bank_count * ROM_BANK_SIZE {
+      return Err(TruncatedFile(data.len()));
     }
 
-    let ram_size = header[0x49];
-    if ram_size != 0x00 {
-      panic!("unsupported RAM size: 0x{:02X}", ram_size);
+    // Patched ROMs may be larger than the header claims, so the bank count is
+    // derived from the actual size
+    let mut rom_banks = data.chunks(ROM_BANK_SIZE)
+                            .map(|bank| bank.to_vec())
+                            .collect::<Vec<Vec<u8>>>();
+    match rom_banks.last_mut() {
+      Some(bank) if bank.len() < ROM_BANK_SIZE => {
+        let missing = ROM_BANK_SIZE - bank.len();
+        bank.grow(missing, 0xff);
+      },
+      _ => (),
+    }
+
+    let ram_size = header.ram_size;
+    let ram_bytes = match ram_bytes(cartridge_type, ram_size) {
+      Some(bytes) => bytes,
+      None => return Err(BadRamSize(ram_size)),
+    };
+
+    // MBC1M collections contain a full header (and thus logo) for every game
+    let multicart =
+      match mbc {
+        Some(MBC1) if rom_banks.len() == MULTICART_ROM_BANKS => {
+          range(1u, 4u).any(|n| header::has_logo_at(data, n * MULTICART_GAME_SIZE))
+        },
+        _ => false,
+      };
+    if multicart {
+      info!("Detected MBC1 multicart");
     }
 
     let cart = Cartridge {
+      header: header,
       title: title,
       cartridge_type: cartridge_type,
       rom_size: rom_size,
       ram_size: ram_size,
       rom_banks: rom_banks,
       rom_bank: 1,
+      bank_high: 0,
+      ram: Vec::from_elem(ram_bytes, 0u8),
+      ram_enabled: false,
+      ram_mode: false,
       mbc: mbc,
+      multicart: multicart,
+      rtc: match cartridge_type {
+        0x0f | 0x10 => Some(rtc::Rtc::new()),
+        _ => None,
+      },
+      rumble: None,
+      rumble_on: false,
+      save_path: None,
     };
 
     Ok(cart)
   }
+
+  pub fn has_battery(&self) -> bool {
+    match self.cartridge_type {
+      0x02 | 0x03 | 0x06 |
+      0x0f | 0x10 | 0x13 |
+      0x1b | 0x1e => true,
+      _ => false,
+    }
+  }
+
+  fn load_ram(&mut self) -> IoResult<()> {
+    let path = match self.save_path {
+      Some(ref path) => path.clone(),
+      None => return Ok(()),
+    };
+    if !path.exists() {
+      return Ok(());
+    }
+
+    let data = try!(File::open(&path).read_to_end());
+    let timestamp = self.restore_save_data(data.as_slice());
+    info!("Loaded {:u} bytes of cartridge RAM from {}", data.len(), path.display());
+
+    match (self.rtc.as_mut(), timestamp) {
+      (Some(rtc), Some(timestamp)) => {
+        // Catch up with the time that passed while not running
+        let elapsed = time::get_time().sec - timestamp;
+        if elapsed > 0 {
+          rtc.advance(elapsed as u64);
+        }
+      },
+      _ => (),
+    }
+    Ok(())
+  }
+
+  // Battery-backed RAM followed by the RTC footer, as stored in .sav files
+  pub fn save_data(&self) -> Vec<u8> {
+    let mut data = self.ram.clone();
+    match self.rtc {
+      Some(ref rtc) => data.push_all(rtc.to_footer(time::get_time().sec).as_slice()),
+      None => (),
+    }
+    data
+  }
+
+  // Restores RAM and RTC from save_data(), without catching up with the time
+  // that has passed since. Returns the RTC footer's timestamp.
+  pub fn restore_save_data(&mut self, data: &[u8]) -> Option<i64> {
+    for (dst, src) in self.ram.iter_mut().zip(data.iter()) {
+      *dst = *src;
+    }
+
+    // The RTC footer follows the RAM contents
+    match self.rtc {
+      Some(ref mut rtc) if data.len() > self.ram.len() => {
+        let timestamp = rtc.from_footer(data.slice_from(self.ram.len()));
+        if timestamp.is_none() {
+          error!("Invalid RTC data");
+        }
+        timestamp
+      },
+      _ => None,
+    }
+  }
+
+  // Keeps the save file untouched, e.g. while replaying a movie
+  pub fn disable_saving(&mut self) {
+    self.save_path = None;
+  }
+
+  // CRC-32 of the ROM contents
+  pub fn rom_checksum(&self) -> u32 {
+    let mut crc = crc32::Crc32::new();
+    for bank in self.rom_banks.iter() {
+      crc.update(bank.as_slice());
+    }
+    crc.value()
+  }
+
+  pub fn has_rumble(&self) -> bool {
+    match self.cartridge_type {
+      0x1c...0x1e => true,
+      _ => false,
+    }
+  }
+
+  pub fn set_rumble_handler(&mut self, handler: Box<RumbleHandler + 'static>) {
+    self.rumble = Some(handler);
+  }
+
+  fn set_rumble(&mut self, on: bool) {
+    if on != self.rumble_on {
+      self.rumble_on = on;
+      match self.rumble {
+        Some(ref mut handler) => handler.set_rumble(on),
+        None => (),
+      }
+    }
+  }
+
+  // Number of lower ROM bank bits wired to the chip (MBC1 only)
+  fn rom_bank_bits(&self) -> uint {
+    if self.multicart { 4 } else { 5 }
+  }
+
+  fn rom_bank0(&self) -> uint {
+    match self.mbc {
+      // In RAM mode the upper bank bits also apply to the lower ROM area
+      Some(MBC1) if self.ram_mode => {
+        (self.bank_high as uint << self.rom_bank_bits()) % self.rom_banks.len()
+      },
+      _ => 0,
+    }
+  }
+
+  fn rom_bankn(&self) -> uint {
+    match self.mbc {
+      Some(MBC1) => {
+        // The zero check applies to all 5 bits, so a multicart can still map
+        // bank 0 of a game (e.g. $10) here
+        let bits = self.rom_bank_bits();
+        let low = self.rom_bank as uint & ((1 << bits) - 1);
+        ((self.bank_high as uint << bits) | low) % self.rom_banks.len()
+      },
+      Some(MBC2) |
+      Some(MBC3) |
+      Some(MBC5) => self.rom_bank as uint % self.rom_banks.len(),
+      None => 1,
+    }
+  }
+
+  fn rtc_selected(&self) -> bool {
+    self.ram_enabled && self.rtc.is_some() && self.bank_high >= 0x08
+  }
+
+  fn ram_addr(&self, addr: u16) -> Option<uint> {
+    if !self.ram_enabled || self.ram.len() == 0 {
+      return None;
+    }
+    let bank =
+      match self.mbc {
+        Some(MBC1) if !self.ram_mode => 0,
+        Some(MBC2) => 0, // Internal RAM is mirrored across the whole area
+        _ => self.bank_high as uint,
+      };
+    let offset = bank * RAM_BANK_SIZE + (addr - 0xa000) as uint;
+    Some(offset % self.ram.len())
+  }
+}
+
+impl Slot for Cartridge {
+  fn tick(&mut self, cycles: u8) {
+    match self.rtc {
+      Some(ref mut rtc) => rtc.tick(cycles),
+      None => (),
+    }
+  }
+
+  fn save_ram(&self) -> IoResult<()> {
+    let path = match self.save_path {
+      Some(ref path) => path,
+      None => return Ok(()),
+    };
+    if self.ram.len() == 0 && self.rtc.is_none() {
+      return Ok(());
+    }
+
+    let mut file = try!(File::create(path));
+    try!(file.write(self.save_data().as_slice()));
+    info!("Saved {:u} bytes of cartridge RAM to {}", self.ram.len(), path.display());
+    Ok(())
+  }
 }
 
 impl Mem for Cartridge {
   fn loadb(&mut self, addr: u16) -> u8 {
     match addr {
-      0x0000...0x3fff => self.rom_banks.get_mut(0).loadb(addr),
-      0x4000...0x7fff => self.rom_banks.get_mut(self.rom_bank as uint).loadb(addr - 0x4000),
-      0xa000...0xbfff => { debug!("RAM load at ${:04X}", addr); 0xff },
+      0x0000...0x3fff => {
+        let bank = self.rom_bank0();
+        self.rom_banks.get_mut(bank).loadb(addr)
+      },
+      0x4000...0x7fff => {
+        let bank = self.rom_bankn();
+        self.rom_banks.get_mut(bank).loadb(addr - 0x4000)
+      },
+      0xa000...0xbfff if self.rtc_selected() => {
+        match self.rtc {
+          Some(ref rtc) => rtc.loadb(self.bank_high),
+          None => 0xff,
+        }
+      },
+      0xa000...0xbfff => {
+        match self.ram_addr(addr) {
+          Some(offset) => {
+            match self.mbc {
+              Some(MBC2) => self.ram[offset] | 0xf0, // Upper nibble is not connected
+              _ => self.ram[offset],
+            }
+          },
+          None => { debug!("RAM load at ${:04X} while disabled", addr); 0xff },
+        }
+      },
       _ => { debug!("unsupported cartridge address ${:04X}", addr); 0xff },
     }
   }
@@ -94,17 +466,105 @@ impl Mem for Cartridge {
       None => info!("store 0x{:02X} in cartridge ROM at ${:04X}", val, addr),
       Some(MBC1) => {
         match addr {
-          0x0000...0x1fff => debug!("RAM enable"),
+          0x0000...0x1fff => { // RAM enable
+            self.ram_enabled = (val & 0x0f) == 0x0a;
+          },
           0x2000...0x3fff => { // set lower 5 bits of ROM bank
-            let bank_bits_0_4 = cmp::max(val & 0b11111, 1u8); // treat 0 as 1
-            self.rom_bank = (self.rom_bank & (0b11100000)) | bank_bits_0_4;
+            self.rom_bank = cmp::max(val & 0b11111, 1u8) as u16; // treat 0 as 1
+          },
+          0x4000...0x5fff => { // set RAM bank or higher 2 bits of ROM bank
+            self.bank_high = val & 0b11;
+          },
+          0x6000...0x7fff => { // ROM/RAM mode select
+            self.ram_mode = (val & 0b1) != 0;
+          },
+          0xa000...0xbfff => {
+            match self.ram_addr(addr) {
+              Some(offset) => *self.ram.get_mut(offset) = val,
+              None => debug!("RAM store at ${:04X} while disabled", addr),
+            }
+          },
+          _ => debug!("unsupported cartridge address ${:04X}", addr),
+        }
+      },
+      Some(MBC2) => {
+        match addr {
+          0x0000...0x3fff if (addr & 0x0100) == 0 => { // RAM enable
+            self.ram_enabled = (val & 0x0f) == 0x0a;
+          },
+          0x0000...0x3fff => { // set 4-bit ROM bank
+            self.rom_bank = cmp::max(val & 0x0f, 1u8) as u16; // treat 0 as 1
+          },
+          0xa000...0xbfff => {
+            match self.ram_addr(addr) {
+              Some(offset) => *self.ram.get_mut(offset) = val & 0x0f,
+              None => debug!("RAM store at ${:04X} while disabled", addr),
+            }
+          },
+          _ => debug!("unsupported cartridge address ${:04X}", addr),
+        }
+      },
+      Some(MBC3) => {
+        match addr {
+          0x0000...0x1fff => { // RAM and RTC enable
+            self.ram_enabled = (val & 0x0f) == 0x0a;
+          },
+          0x2000...0x3fff => { // set 7-bit ROM bank
+            self.rom_bank = cmp::max(val & 0x7f, 1u8) as u16; // treat 0 as 1
+          },
+          0x4000...0x5fff => { // select RAM bank (0x00-0x03) or RTC register (0x08-0x0c)
+            match val {
+              0x00...0x03 | 0x08...0x0c => self.bank_high = val,
+              _ => debug!("invalid RAM bank/RTC register select 0x{:02X}", val),
+            }
+          },
+          0x6000...0x7fff => { // latch clock data
+            match self.rtc {
+              Some(ref mut rtc) => rtc.latch(val),
+              None => (),
+            }
+          },
+          0xa000...0xbfff if self.rtc_selected() => {
+            match self.rtc {
+              Some(ref mut rtc) => rtc.storeb(self.bank_high, val),
+              None => (),
+            }
+          },
+          0xa000...0xbfff => {
+            match self.ram_addr(addr) {
+              Some(offset) => *self.ram.get_mut(offset) = val,
+              None => debug!("RAM store at ${:04X} while disabled", addr),
+            }
+          },
+          _ => debug!("unsupported cartridge address ${:04X}", addr),
+        }
+      },
+      Some(MBC5) => {
+        match addr {
+          0x0000...0x1fff => { // RAM enable
+            self.ram_enabled = (val & 0x0f) == 0x0a;
+          },
+          0x2000...0x2fff => { // set lower 8 bits of ROM bank (bank 0 is selectable)
+            self.rom_bank = (self.rom_bank & 0x100) | val as u16;
+          },
+          0x3000...0x3fff => { // set 9th bit of ROM bank
+            self.rom_bank = (self.rom_bank & 0xff) | ((val as u16 & 0b1) << 8);
+          },
+          0x4000...0x5fff => { // set RAM bank
+            if self.has_rumble() {
+              // Bit 3 drives the rumble motor instead of selecting a bank
+              self.set_rumble((val & 0b1000) != 0);
+              self.bank_high = val & 0b0111;
+            } else {
+              self.bank_high = val & 0b1111;
+            }
           },
-          0x4000...0x5fff => { // set higher 2 bits of ROM bank
-            let bank_bits_5_6 = (val & 0b11) << 5;
-            self.rom_bank = (self.rom_bank & (0b10011111)) | bank_bits_5_6;
+          0xa000...0xbfff => {
+            match self.ram_addr(addr) {
+              Some(offset) => *self.ram.get_mut(offset) = val,
+              None => debug!("RAM store at ${:04X} while disabled", addr),
+            }
           },
-          0x6000...0x7fff => debug!("ROM/RAM mode select at ${:04X}", addr),
-          0xa000...0xbfff => debug!("RAM store at ${:04X}", addr),
           _ => debug!("unsupported cartridge address ${:04X}", addr),
         }
       },
@@ -0,0 +1,182 @@
+use std::io::{BufferedReader, File, IoResult};
+
+//
+// Cheat codes (Game Genie, GameShark)
+//
+
+pub enum CheatKind {
+  // Replaces ROM reads at an address, optionally only if the original byte matches
+  GameGenie(u16, u8, Option<u8>), // Address, new value, compare value
+  // Writes a value to RAM once per frame
+  GameShark(u16, u8),             // Address, value
+}
+
+pub struct Cheat {
+  pub code: String,
+  pub description: String,
+  pub kind: CheatKind,
+  pub enabled: bool,
+}
+
+pub struct Cheats {
+  cheats: Vec<Cheat>,
+  locked: bool, // No changes allowed, e.g. while a movie is active
+}
+
+fn hex_digits(s: &str) -> Option<Vec<u8>> {
+  let mut digits = Vec::with_capacity(s.len());
+  for c in s.chars() {
+    match c.to_digit(16) {
+      Some(d) => digits.push(d as u8),
+      None => return None,
+    }
+  }
+  Some(digits)
+}
+
+// Parses a Game Genie code of the form ABC-DEF or ABC-DEF-GHI.
+fn parse_game_genie(code: &str) -> Option<CheatKind> {
+  let stripped = code.chars().filter(|&c| c != '-').collect::<String>();
+  if stripped.len() != 6 && stripped.len() != 9 {
+    return None;
+  }
+  let d = match hex_digits(stripped.as_slice()) {
+    Some(d) => d,
+    None => return None,
+  };
+
+  let value = (d[0] << 4) | d[1];
+  let addr = (((d[5] ^ 0xf) as u16) << 12) | (d[2] as u16 << 8) | (d[3] as u16 << 4) | d[4] as u16;
+  let compare =
+    if d.len() == 9 {
+      // G and I hold the compare value XORed with $BA and rotated left by 2
+      let encoded = (d[6] << 4) | d[8];
+      Some(((encoded >> 2) | (encoded << 6)) ^ 0xba)
+    } else {
+      None
+    };
+
+  Some(GameGenie(addr, value, compare))
+}
+
+// Parses a GameShark code of the form TTVVAAAA, with the address little-endian.
+fn parse_game_shark(code: &str) -> Option<CheatKind> {
+  if code.len() != 8 {
+    return None;
+  }
+  let d = match hex_digits(code) {
+    Some(d) => d,
+    None => return None,
+  };
+
+  let value = (d[2] << 4) | d[3];
+  let addr = ((d[6] as u16) << 12) | ((d[7] as u16) << 8) | ((d[4] as u16) << 4) | d[5] as u16;
+  Some(GameShark(addr, value))
+}
+
+pub fn parse(code: &str) -> Option<CheatKind> {
+  if code.contains_char('-') {
+    parse_game_genie(code)
+  } else {
+    parse_game_shark(code).or_else(|| parse_game_genie(code))
+  }
+}
+
+impl Cheats {
+  pub fn new() -> Cheats {
+    Cheats { cheats: vec!(), locked: false }
+  }
+
+  // Prevents adding or toggling cheats from now on
+  pub fn lock(&mut self) {
+    self.locked = true;
+  }
+
+  pub fn is_locked(&self) -> bool {
+    self.locked
+  }
+
+  // Loads cheats from a text file with one code per line, optionally followed
+  // by a description. Lines starting with '#' are comments, and codes prefixed
+  // with '!' start out disabled.
+  pub fn load(&mut self, path: &Path) -> IoResult<()> {
+    let mut reader = BufferedReader::new(try!(File::open(path)));
+    for line in reader.lines() {
+      let line = try!(line);
+      let line = line.as_slice().trim();
+      if line.len() == 0 || line.starts_with("#") {
+        continue;
+      }
+
+      let (code, description) =
+        match line.find(|c: char| c.is_whitespace()) {
+          Some(i) => (line.slice_to(i), line.slice_from(i).trim()),
+          None => (line, ""),
+        };
+      let (code, enabled) =
+        if code.starts_with("!") { (code.slice_from(1), false) } else { (code, true) };
+
+      if !self.add(code, description, enabled) {
+        error!("Invalid cheat code in {}: {:s}", path.display(), code);
+      }
+    }
+    Ok(())
+  }
+
+  pub fn add(&mut self, code: &str, description: &str, enabled: bool) -> bool {
+    match parse(code) {
+      Some(kind) => {
+        self.cheats.push(Cheat {
+          code: code.chars().map(|c| c.to_uppercase()).collect(),
+          description: description.to_string(),
+          kind: kind,
+          enabled: enabled,
+        });
+        true
+      },
+      None => false,
+    }
+  }
+
+  pub fn list(&self) -> &[Cheat] {
+    self.cheats.as_slice()
+  }
+
+  // Toggles a cheat and returns its new state, or None if there is no such cheat.
+  pub fn toggle(&mut self, index: uint) -> Option<bool> {
+    if index >= self.cheats.len() {
+      return None;
+    }
+    let cheat = self.cheats.get_mut(index);
+    cheat.enabled = !cheat.enabled;
+    Some(cheat.enabled)
+  }
+
+  // Applies Game Genie codes to a byte read from ROM.
+  pub fn patch_rom(&self, addr: u16, val: u8) -> u8 {
+    for cheat in self.cheats.iter().filter(|c| c.enabled) {
+      match cheat.kind {
+        GameGenie(a, new, compare) if a == addr => {
+          match compare {
+            Some(old) if old != val => (),
+            _ => return new,
+          }
+        },
+        _ => (),
+      }
+    }
+    val
+  }
+
+  // Returns the (address, value) pairs of all GameShark codes, which are to
+  // be written at every V-Blank.
+  pub fn ram_writes(&self) -> Vec<(u16, u8)> {
+    self.cheats.iter()
+               .filter(|c| c.enabled)
+               .filter_map(|c| match c.kind {
+                 GameShark(addr, val) => Some((addr, val)),
+                 _ => None,
+               })
+               .collect()
+  }
+}
@@ -549,6 +549,7 @@ pub struct Cpu<M> {
   pub regs: Regs,
   ime: bool,
   halted: bool,
+  stopped: bool,
   pub cycles: u64,
   pub mem: M,
 }
@@ -559,12 +560,28 @@ impl<M: mem::Mem> Cpu<M> {
       regs: Regs::new(),
       ime: false, // TODO: Are interrupts enabled at boot?
       halted: false,
+      stopped: false,
       cycles: 0u64,
       mem: mem,
     }
   }
 
+  // Whether the CPU (and LCD) wait in STOP mode for a button press
+  pub fn is_stopped(&self) -> bool {
+    self.stopped
+  }
+
   pub fn step(&mut self) -> u8 {
+    if self.stopped {
+      if (self.mem.loadb(0xff00) & 0x0f) != 0x0f {
+        // Wake up on a selected button being pressed
+        self.stopped = false;
+      } else {
+        self.cycles += 4;
+        return 4;
+      }
+    }
+
     if self.halted {
       if self.mem.loadb(0xff0f) != 0 {
         // Wake up on interrupt
@@ -686,8 +703,9 @@ impl<M: mem::Mem> Decoder<u8> for Cpu<M> {
     4
   }
 
-  fn stop(&mut self, val: u8) -> u8 {
-    //panic!("instruction not implemented: stop")
+  fn stop(&mut self, _: u8) -> u8 {
+    self.stopped = true;
+    self.mem.storeb(0xff04, 0); // STOP resets the divider
     4
   }
 
@@ -0,0 +1,40 @@
+//
+// CRC-32 (IEEE 802.3), as used by UPS/BPS patches, zip, gzip and PNG
+//
+
+const POLYNOMIAL: u32 = 0xedb88320;
+
+pub struct Crc32 {
+  table: [u32, ..256],
+  value: u32,
+}
+
+impl Crc32 {
+  pub fn new() -> Crc32 {
+    let mut table = [0u32, ..256];
+    for n in range(0u, 256u) {
+      let mut c = n as u32;
+      for _ in range(0u, 8u) {
+        c = if (c & 1) != 0 { POLYNOMIAL ^ (c >> 1) } else { c >> 1 };
+      }
+      table[n] = c;
+    }
+    Crc32 { table: table, value: 0xffffffff }
+  }
+
+  pub fn update(&mut self, data: &[u8]) {
+    for &b in data.iter() {
+      self.value = self.table[((self.value ^ b as u32) & 0xff) as uint] ^ (self.value >> 8);
+    }
+  }
+
+  pub fn value(&self) -> u32 {
+    self.value ^ 0xffffffff
+  }
+}
+
+pub fn checksum(data: &[u8]) -> u32 {
+  let mut crc = Crc32::new();
+  crc.update(data);
+  crc.value()
+}
@@ -1,6 +1,8 @@
 use mem::Mem;
+use cheats;
 use cpu;
 use disasm;
+use sound;
 use std::io::stdio::{print, println};
 use std::io::{stdio, File, BufferedReader, IoResult};
 use std::num::from_str_radix;
@@ -158,7 +160,8 @@ fn dump_bg<M: Mem>(m: &mut M) -> IoResult<()> {
   write_pgm(&Path::new("bg.pgm"), 32*8, 32*8, data)
 }
 
-fn parse_addr(s: &str) -> Option<u16> {
+// Parses a decimal number, or a hex one prefixed with '$'
+pub fn parse_addr(s: &str) -> Option<u16> {
   let mut slice = s;
   let mut radix = 10;
   if slice.starts_with("$") {
@@ -168,6 +171,50 @@ fn parse_addr(s: &str) -> Option<u16> {
   from_str_radix::<u16>(slice, radix)
 }
 
+fn show_cheats(cheats: &cheats::Cheats) {
+  if cheats.list().len() == 0 {
+    println("No cheats");
+  } else {
+    println("Cheats:");
+    for (i, cheat) in cheats.list().iter().enumerate() {
+      println!("  {:u}: [{:s}] {:s} {:s}",
+               i,
+               if cheat.enabled { "x" } else { " " },
+               cheat.code,
+               cheat.description);
+    }
+  }
+}
+
+fn show_channels(sound: &sound::Sound) {
+  println("Channels:");
+  for channel in range(0u, sound::CHANNEL_COUNT) {
+    let status =
+      if sound.solo() == Some(channel) {
+        "solo"
+      } else if sound.solo().is_some() || sound.is_muted(channel) {
+        "muted"
+      } else {
+        "on"
+      };
+    println!("  {:u}: {:s}", channel + 1, status);
+  }
+}
+
+// Parses a 1-based sound channel number
+fn parse_channel(s: &str) -> Option<uint> {
+  match from_str::<uint>(s) {
+    Some(n) if n >= 1 && n <= sound::CHANNEL_COUNT => Some(n - 1),
+    _ => None,
+  }
+}
+
+// Access to emulator state beyond the memory bus
+pub trait DebugTarget: Mem {
+  fn cheats(&mut self) -> &mut cheats::Cheats;
+  fn sound(&mut self) -> &mut sound::Sound;
+}
+
 pub struct Debugger {
   breakpoints: Vec<u16>,
 }
@@ -213,7 +260,7 @@ impl Debugger {
     }
   }
 
-  fn dispatch<M: Mem>(&mut self, cpu: &mut cpu::Cpu<M>, words: Vec<&str>) -> Option<DebuggerCommand> {
+  fn dispatch<M: DebugTarget>(&mut self, cpu: &mut cpu::Cpu<M>, words: Vec<&str>) -> Option<DebuggerCommand> {
     if words.len() == 0 {
       return None;
     }
@@ -268,6 +315,52 @@ impl Debugger {
         }
         None
       },
+      "cheat" => { // list, toggle or add cheats
+        let cheats = cpu.mem.cheats();
+        if words.len() == 1 {
+          show_cheats(cheats);
+        } else if cheats.is_locked() {
+          error!("Cheats can't be changed while a movie is recorded or played");
+        } else if words[1] == "add" && words.len() >= 3 {
+          let description = words.slice_from(3).connect(" ");
+          if cheats.add(words[2], description.as_slice(), true) {
+            println!("Cheat {:s} added", words[2]);
+          } else {
+            error!("Invalid cheat code: {:s}", words[2]);
+          }
+        } else {
+          match from_str::<uint>(words[1]).and_then(|i| cheats.toggle(i)) {
+            Some(true)  => println!("Cheat {:s} enabled", words[1]),
+            Some(false) => println!("Cheat {:s} disabled", words[1]),
+            None        => error!("No such cheat: {:s}", words[1]),
+          }
+        }
+        None
+      },
+      "mute" => { // list or toggle muted sound channels
+        let sound = cpu.mem.sound();
+        if words.len() >= 2 {
+          match parse_channel(words[1]) {
+            Some(channel) => { sound.toggle_mute(channel); },
+            None => error!("Invalid channel: {:s}", words[1]),
+          }
+        }
+        show_channels(sound);
+        None
+      },
+      "solo" => { // solo a sound channel, or unsolo all
+        let sound = cpu.mem.sound();
+        if words.len() >= 2 {
+          match parse_channel(words[1]) {
+            Some(channel) => { sound.toggle_solo(channel); },
+            None => error!("Invalid channel: {:s}", words[1]),
+          }
+        } else {
+          sound.clear_solo();
+        }
+        show_channels(sound);
+        None
+      },
       "tiles" => { // dump video tiles
         match dump_tiles(&mut cpu.mem) {
           Err(e) => error!("I/O error: {}", e),
@@ -289,7 +382,7 @@ impl Debugger {
     }
   }
 
-  pub fn prompt<M: Mem>(&mut self, cpu: &mut cpu::Cpu<M>) -> DebuggerCommand {
+  pub fn prompt<M: DebugTarget>(&mut self, cpu: &mut cpu::Cpu<M>) -> DebuggerCommand {
     let mut stdin = BufferedReader::new(stdio::stdin());
 
     loop {
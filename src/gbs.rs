@@ -0,0 +1,228 @@
+use cartridge;
+use cpu;
+use interrupt;
+use mem::Mem;
+use std::cmp;
+use std::fmt;
+use std::io::{File, IoError, IoResult};
+
+//
+// Statics
+//
+
+const GBS_MAGIC: &'static [u8] = b"GBS";
+const HEADER_SIZE: uint = 0x70;
+const ROM_BANK_SIZE: uint = 0x4000;
+const RAM_SIZE: uint = 0x2000;
+
+const TAC_START: u8 = 0x04;
+
+// Driver code placed below the load address. RST vectors are redirected to
+// the load address as required by the format, the interrupt vectors call the
+// play routine, and the idle loop waits for interrupts.
+const IDLE_LOOP_ADDR: u16 = 0x0100;
+const VBLANK_VECTOR: u16 = 0x0040;
+const TIMER_VECTOR: u16 = 0x0050;
+
+const OP_JP: u8 = 0xc3;
+const OP_CALL: u8 = 0xcd;
+const OP_RETI: u8 = 0xd9;
+const OP_EI: u8 = 0xfb;
+const OP_HALT: u8 = 0x76;
+const OP_JR: u8 = 0x18;
+
+
+pub enum GbsError {
+  IoFailure(IoError),
+  NotGbs,
+  TruncatedFile(uint),
+  BadLoadAddress(u16),
+}
+
+impl fmt::Show for GbsError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      IoFailure(ref e)     => write!(f, "I/O error: {}", e),
+      NotGbs               => write!(f, "not a GBS file"),
+      TruncatedFile(size)  => write!(f, "file is truncated ({:u} bytes)", size),
+      BadLoadAddress(addr) => write!(f, "invalid load address ${:04X}", addr),
+    }
+  }
+}
+
+pub fn is_gbs(data: &[u8]) -> bool {
+  data.starts_with(GBS_MAGIC)
+}
+
+
+//
+// GBS Header
+//
+
+#[deriving(Clone)]
+pub struct GbsHeader {
+  pub version: u8,
+  pub song_count: u8,
+  pub first_song: u8, // 1-based
+  pub load_addr: u16,
+  pub init_addr: u16,
+  pub play_addr: u16,
+  pub stack_pointer: u16,
+  pub timer_modulo: u8,
+  pub timer_control: u8,
+  pub title: String,
+  pub author: String,
+  pub copyright: String,
+}
+
+fn le16(data: &[u8], pos: uint) -> u16 {
+  data[pos] as u16 | (data[pos + 1] as u16 << 8)
+}
+
+fn text(data: &[u8]) -> String {
+  let bytes = data.iter().take_while(|&&b| b != 0).map(|&b| b).collect::<Vec<u8>>();
+  String::from_utf8_lossy(bytes.as_slice()).into_string()
+}
+
+impl GbsHeader {
+  fn parse(h: &[u8]) -> GbsHeader {
+    GbsHeader {
+      version: h[0x03],
+      song_count: h[0x04],
+      first_song: h[0x05],
+      load_addr: le16(h, 0x06),
+      init_addr: le16(h, 0x08),
+      play_addr: le16(h, 0x0a),
+      stack_pointer: le16(h, 0x0c),
+      timer_modulo: h[0x0e],
+      timer_control: h[0x0f],
+      title: text(h.slice(0x10, 0x30)),
+      author: text(h.slice(0x30, 0x50)),
+      copyright: text(h.slice(0x50, 0x70)),
+    }
+  }
+
+  // Play is driven by the timer interrupt instead of V-Blank
+  pub fn uses_timer(&self) -> bool {
+    (self.timer_control & TAC_START) != 0
+  }
+}
+
+
+//
+// GBS ROM
+//
+
+// Maps the music data like a cartridge ROM with simple bank switching
+// (writes to 0x2000-0x3fff select the upper bank) and 8 KiB of RAM.
+pub struct GbsRom {
+  pub header: GbsHeader,
+  rom_banks: Vec<Vec<u8>>,
+  rom_bank: uint,
+  ram: Vec<u8>,
+}
+
+impl GbsRom {
+  pub fn from_path(path: &Path) -> Result<GbsRom, GbsError> {
+    let data = try!(File::open(path).read_to_end().map_err(IoFailure));
+    GbsRom::from_bytes(data.as_slice())
+  }
+
+  pub fn from_bytes(data: &[u8]) -> Result<GbsRom, GbsError> {
+    if !is_gbs(data) {
+      return Err(NotGbs);
+    }
+    if data.len() < HEADER_SIZE {
+      return Err(TruncatedFile(data.len()));
+    }
+    let header = GbsHeader::parse(data.slice_to(HEADER_SIZE));
+    let load_addr = header.load_addr as uint;
+    if load_addr < 0x400 || load_addr >= 0x8000 {
+      return Err(BadLoadAddress(header.load_addr));
+    }
+
+    // Build a ROM image with the data at the load address and the driver below
+    let mut image = Vec::from_elem(load_addr, 0xffu8);
+    image.push_all(data.slice_from(HEADER_SIZE));
+    let banks = (image.len() + ROM_BANK_SIZE - 1) / ROM_BANK_SIZE;
+    image.grow(banks * ROM_BANK_SIZE - image.len(), 0xff);
+
+    for rst in range(0u, 8u) {
+      let target = header.load_addr + rst as u16 * 8;
+      let code = [OP_JP, target as u8, (target >> 8) as u8];
+      write_code(&mut image, rst * 8, code);
+    }
+    let play = [OP_CALL, header.play_addr as u8, (header.play_addr >> 8) as u8, OP_RETI];
+    write_code(&mut image, VBLANK_VECTOR as uint, play);
+    write_code(&mut image, TIMER_VECTOR as uint, play);
+    write_code(&mut image, IDLE_LOOP_ADDR as uint, [OP_EI, OP_HALT, OP_JR, 0xfc]);
+
+    let rom_banks = image.as_slice()
+                         .chunks(ROM_BANK_SIZE)
+                         .map(|bank| bank.to_vec())
+                         .collect::<Vec<Vec<u8>>>();
+
+    Ok(GbsRom {
+      header: header,
+      rom_banks: rom_banks,
+      rom_bank: 1,
+      ram: Vec::from_elem(RAM_SIZE, 0u8),
+    })
+  }
+}
+
+fn write_code(image: &mut Vec<u8>, addr: uint, code: &[u8]) {
+  for (i, &b) in code.iter().enumerate() {
+    *image.get_mut(addr + i) = b;
+  }
+}
+
+// Prepares the CPU and hardware to play a song (0-based): sets up the timer
+// and interrupts, then calls the init routine, which returns to the idle loop.
+pub fn init_song<M: Mem>(cpu: &mut cpu::Cpu<M>, header: &GbsHeader, song: u8) {
+  // Sound on, full volume on both outputs
+  cpu.mem.storeb(0xff26, 0x80);
+  cpu.mem.storeb(0xff25, 0xff);
+  cpu.mem.storeb(0xff24, 0x77);
+
+  cpu.mem.storeb(0xff06, header.timer_modulo);
+  cpu.mem.storeb(0xff07, header.timer_control);
+  cpu.mem.storeb(0xff0f, 0x00);
+  cpu.mem.storeb(0xffff, if header.uses_timer() { interrupt::IRQ_TIMER } else { interrupt::IRQ_VBLANK });
+
+  cpu.regs.a = song;
+  cpu.regs.sp = header.stack_pointer - 2;
+  cpu.mem.storew(cpu.regs.sp, IDLE_LOOP_ADDR);
+  cpu.regs.pc = header.init_addr;
+}
+
+impl cartridge::Slot for GbsRom {
+  fn tick(&mut self, _cycles: u8) {
+  }
+
+  fn save_ram(&self) -> IoResult<()> {
+    Ok(())
+  }
+}
+
+impl Mem for GbsRom {
+  fn loadb(&mut self, addr: u16) -> u8 {
+    match addr {
+      0x0000...0x3fff => self.rom_banks[0][addr as uint],
+      0x4000...0x7fff => {
+        let bank = self.rom_bank % self.rom_banks.len();
+        self.rom_banks[bank][(addr - 0x4000) as uint]
+      },
+      0xa000...0xbfff => self.ram[(addr - 0xa000) as uint],
+      _ => { debug!("unsupported GBS address ${:04X}", addr); 0xff },
+    }
+  }
+
+  fn storeb(&mut self, addr: u16, val: u8) {
+    match addr {
+      0x2000...0x3fff => self.rom_bank = cmp::max(val, 1) as uint, // treat 0 as 1
+      0xa000...0xbfff => *self.ram.get_mut((addr - 0xa000) as uint) = val,
+      _ => debug!("store 0x{:02X} in GBS ROM at ${:04X}", val, addr),
+    }
+  }
+}
@@ -0,0 +1,133 @@
+//
+// Statics
+//
+
+pub const HEADER_START: uint = 0x100;
+pub const HEADER_END: uint = 0x150;
+
+// Logo bitmap checked by the boot ROM at $0104-$0133
+const NINTENDO_LOGO: [u8, ..48] = [
+  0xce, 0xed, 0x66, 0x66, 0xcc, 0x0d, 0x00, 0x0b, 0x03, 0x73, 0x00, 0x83,
+  0x00, 0x0c, 0x00, 0x0d, 0x00, 0x08, 0x11, 0x1f, 0x88, 0x89, 0x00, 0x0e,
+  0xdc, 0xcc, 0x6e, 0xe6, 0xdd, 0xdd, 0xd9, 0x99, 0xbb, 0xbb, 0x67, 0x63,
+  0x6e, 0x0e, 0xec, 0xcc, 0xdd, 0xdc, 0x99, 0x9f, 0xbb, 0xb9, 0x33, 0x3e,
+];
+
+const LOGO_OFFSET: uint = 0x104;
+
+const NEW_LICENSEE_MARKER: u8 = 0x33;
+
+
+//
+// Cartridge Header
+//
+
+pub struct Header {
+  pub logo: [u8, ..48],          // $0104-$0133
+  pub title: Vec<u8>,            // $0134-$0142 (raw, may include manufacturer code)
+  pub manufacturer_code: Vec<u8>, // $013F-$0142
+  pub cgb_flag: u8,              // $0143
+  pub new_licensee_code: Vec<u8>, // $0144-$0145
+  pub sgb_flag: u8,              // $0146
+  pub cartridge_type: u8,        // $0147
+  pub rom_size: u8,              // $0148
+  pub ram_size: u8,              // $0149
+  pub destination: u8,           // $014A
+  pub old_licensee_code: u8,     // $014B
+  pub version: u8,               // $014C
+  pub header_checksum: u8,       // $014D
+  pub global_checksum: u16,      // $014E-$014F (big-endian)
+
+  computed_header_checksum: u8,
+  computed_global_checksum: u16,
+}
+
+// Checks for the Nintendo logo in a header starting at the given ROM offset.
+pub fn has_logo_at(rom: &[u8], base: uint) -> bool {
+  let start = base + LOGO_OFFSET;
+  let end = start + NINTENDO_LOGO.len();
+  end <= rom.len() && rom.slice(start, end) == NINTENDO_LOGO.as_slice()
+}
+
+impl Header {
+  // Parses the header of a ROM image, which must be at least HEADER_END bytes long.
+  pub fn parse(rom: &[u8]) -> Header {
+    let h = rom.slice(HEADER_START, HEADER_END);
+
+    let mut logo = [0u8, ..48];
+    for (dst, src) in logo.iter_mut().zip(h.slice(0x04, 0x34).iter()) {
+      *dst = *src;
+    }
+
+    // x = x - byte - 1 over $0134-$014C, as computed by the boot ROM
+    let computed_header_checksum =
+      h.slice(0x34, 0x4d).iter().fold(0u8, |x, &b| x - b - 1);
+
+    // Sum of all ROM bytes except the global checksum itself
+    let computed_global_checksum =
+      rom.iter().enumerate()
+         .filter(|&(i, _)| i != 0x14e && i != 0x14f)
+         .fold(0u16, |sum, (_, &b)| sum + b as u16);
+
+    Header {
+      logo: logo,
+      title: h.slice(0x34, 0x43).to_vec(),
+      manufacturer_code: h.slice(0x3f, 0x43).to_vec(),
+      cgb_flag: h[0x43],
+      new_licensee_code: h.slice(0x44, 0x46).to_vec(),
+      sgb_flag: h[0x46],
+      cartridge_type: h[0x47],
+      rom_size: h[0x48],
+      ram_size: h[0x49],
+      destination: h[0x4a],
+      old_licensee_code: h[0x4b],
+      version: h[0x4c],
+      header_checksum: h[0x4d],
+      global_checksum: (h[0x4e] as u16 << 8) | h[0x4f] as u16,
+      computed_header_checksum: computed_header_checksum,
+      computed_global_checksum: computed_global_checksum,
+    }
+  }
+
+  // The boot ROM locks up unless both the logo and the header checksum match.
+  pub fn logo_valid(&self) -> bool {
+    self.logo.as_slice() == NINTENDO_LOGO.as_slice()
+  }
+
+  pub fn header_checksum_valid(&self) -> bool {
+    self.header_checksum == self.computed_header_checksum
+  }
+
+  // Not verified by the hardware, but a good indicator of a bad dump
+  pub fn global_checksum_valid(&self) -> bool {
+    self.global_checksum == self.computed_global_checksum
+  }
+
+  pub fn computed_header_checksum(&self) -> u8 {
+    self.computed_header_checksum
+  }
+
+  pub fn computed_global_checksum(&self) -> u16 {
+    self.computed_global_checksum
+  }
+
+  pub fn uses_new_licensee_code(&self) -> bool {
+    self.old_licensee_code == NEW_LICENSEE_MARKER
+  }
+
+  pub fn supports_cgb(&self) -> bool {
+    (self.cgb_flag & 0x80) != 0
+  }
+
+  pub fn requires_cgb(&self) -> bool {
+    self.cgb_flag == 0xc0
+  }
+
+  pub fn supports_sgb(&self) -> bool {
+    self.sgb_flag == 0x03
+  }
+
+  pub fn is_japanese(&self) -> bool {
+    self.destination == 0x00
+  }
+}
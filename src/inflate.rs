@@ -0,0 +1,265 @@
+//
+// DEFLATE decoder (RFC 1951), used for compressed ROM archives
+//
+
+const MAX_BITS: uint = 15;
+const MAX_LIT_CODES: uint = 288;
+const MAX_DIST_CODES: uint = 30;
+
+const LENGTH_BASE: [u16, ..29] = [
+  3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+  35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8, ..29] = [
+  0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+  3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16, ..30] = [
+  1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+  257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+  8193, 12289, 16385, 24577];
+const DIST_EXTRA: [u8, ..30] = [
+  0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+  7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+// Order in which code length code lengths are stored in dynamic blocks
+const CODE_LENGTH_ORDER: [uint, ..19] = [
+  16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+pub type InflateResult<T> = Result<T, &'static str>;
+
+struct BitReader<'a> {
+  data: &'a [u8],
+  pos: uint,      // Next byte to read
+  bit_buf: u32,   // Buffered bits, LSB first
+  bit_count: uint,
+}
+
+impl<'a> BitReader<'a> {
+  fn bits(&mut self, count: uint) -> InflateResult<u32> {
+    while self.bit_count < count {
+      if self.pos >= self.data.len() {
+        return Err("unexpected end of compressed data");
+      }
+      self.bit_buf |= self.data[self.pos] as u32 << self.bit_count;
+      self.pos += 1;
+      self.bit_count += 8;
+    }
+    let val = self.bit_buf & ((1u32 << count) - 1);
+    self.bit_buf >>= count;
+    self.bit_count -= count;
+    Ok(val)
+  }
+
+  fn align(&mut self) {
+    self.bit_buf = 0;
+    self.bit_count = 0;
+  }
+}
+
+// Canonical Huffman code, stored as the number of codes per length and the
+// symbols ordered by code
+struct Huffman {
+  counts: [u16, ..MAX_BITS + 1],
+  symbols: Vec<u16>,
+}
+
+impl Huffman {
+  fn new(lengths: &[u8]) -> InflateResult<Huffman> {
+    let mut counts = [0u16, ..MAX_BITS + 1];
+    for &len in lengths.iter() {
+      counts[len as uint] += 1;
+    }
+
+    // Check for an over-subscribed code
+    let mut left = 1i;
+    for len in range(1u, MAX_BITS + 1) {
+      left <<= 1;
+      left -= counts[len] as int;
+      if left < 0 {
+        return Err("over-subscribed Huffman code");
+      }
+    }
+
+    let mut offsets = [0u16, ..MAX_BITS + 1];
+    for len in range(1u, MAX_BITS) {
+      offsets[len + 1] = offsets[len] + counts[len];
+    }
+
+    let mut symbols = Vec::from_elem(lengths.len(), 0u16);
+    for (symbol, &len) in lengths.iter().enumerate() {
+      if len != 0 {
+        *symbols.get_mut(offsets[len as uint] as uint) = symbol as u16;
+        offsets[len as uint] += 1;
+      }
+    }
+
+    Ok(Huffman { counts: counts, symbols: symbols })
+  }
+
+  fn decode(&self, r: &mut BitReader) -> InflateResult<u16> {
+    let mut code = 0i;  // Bits read so far
+    let mut first = 0i; // First code of the current length
+    let mut index = 0i; // Index of the first symbol of the current length
+    for len in range(1u, MAX_BITS + 1) {
+      code |= try!(r.bits(1)) as int;
+      let count = self.counts[len] as int;
+      if code - first < count {
+        return Ok(self.symbols[(index + code - first) as uint]);
+      }
+      index += count;
+      first = (first + count) << 1;
+      code <<= 1;
+    }
+    Err("invalid Huffman code")
+  }
+}
+
+// Decompresses data, failing once the output would exceed max_size bytes
+pub fn inflate(data: &[u8], max_size: uint) -> InflateResult<Vec<u8>> {
+  let mut r = BitReader { data: data, pos: 0, bit_buf: 0, bit_count: 0 };
+  let mut out = Vec::new();
+
+  loop {
+    let last = try!(r.bits(1)) == 1;
+    match try!(r.bits(2)) {
+      0 => try!(stored_block(&mut r, &mut out, max_size)),
+      1 => {
+        let (lit, dist) = try!(fixed_codes());
+        try!(compressed_block(&mut r, &mut out, max_size, &lit, &dist));
+      },
+      2 => {
+        let (lit, dist) = try!(dynamic_codes(&mut r));
+        try!(compressed_block(&mut r, &mut out, max_size, &lit, &dist));
+      },
+      _ => return Err("invalid block type"),
+    }
+    if last {
+      break;
+    }
+  }
+
+  Ok(out)
+}
+
+fn stored_block(r: &mut BitReader, out: &mut Vec<u8>, max_size: uint) -> InflateResult<()> {
+  r.align();
+  if r.pos + 4 > r.data.len() {
+    return Err("unexpected end of compressed data");
+  }
+  let len = r.data[r.pos] as uint | (r.data[r.pos + 1] as uint << 8);
+  let nlen = r.data[r.pos + 2] as uint | (r.data[r.pos + 3] as uint << 8);
+  if len != (!nlen & 0xffff) {
+    return Err("stored block length mismatch");
+  }
+  r.pos += 4;
+  if r.pos + len > r.data.len() {
+    return Err("unexpected end of compressed data");
+  }
+  if out.len() + len > max_size {
+    return Err("decompressed data too large");
+  }
+  out.push_all(r.data.slice(r.pos, r.pos + len));
+  r.pos += len;
+  Ok(())
+}
+
+fn fixed_codes() -> InflateResult<(Huffman, Huffman)> {
+  let mut lengths = [0u8, ..MAX_LIT_CODES];
+  for (symbol, len) in lengths.iter_mut().enumerate() {
+    *len = match symbol {
+      0...143   => 8,
+      144...255 => 9,
+      256...279 => 7,
+      _         => 8,
+    };
+  }
+  let lit = try!(Huffman::new(lengths));
+  let dist = try!(Huffman::new([5u8, ..MAX_DIST_CODES]));
+  Ok((lit, dist))
+}
+
+fn dynamic_codes(r: &mut BitReader) -> InflateResult<(Huffman, Huffman)> {
+  let nlen = try!(r.bits(5)) as uint + 257;
+  let ndist = try!(r.bits(5)) as uint + 1;
+  let ncode = try!(r.bits(4)) as uint + 4;
+  if nlen > MAX_LIT_CODES || ndist > MAX_DIST_CODES {
+    return Err("too many length or distance codes");
+  }
+
+  let mut code_lengths = [0u8, ..19];
+  for i in range(0, ncode) {
+    code_lengths[CODE_LENGTH_ORDER[i]] = try!(r.bits(3)) as u8;
+  }
+  let code_length_code = try!(Huffman::new(code_lengths));
+
+  // Literal/length and distance code lengths share one run-length coded sequence
+  let mut lengths = Vec::with_capacity(nlen + ndist);
+  while lengths.len() < nlen + ndist {
+    let symbol = try!(code_length_code.decode(r));
+    let (val, repeat) =
+      match symbol {
+        0...15 => (symbol as u8, 1),
+        16 => {
+          let prev = match lengths.last() {
+            Some(&len) => len,
+            None => return Err("repeat with no previous length"),
+          };
+          (prev, 3 + try!(r.bits(2)) as uint)
+        },
+        17 => (0, 3 + try!(r.bits(3)) as uint),
+        _  => (0, 11 + try!(r.bits(7)) as uint),
+      };
+    if lengths.len() + repeat > nlen + ndist {
+      return Err("too many code lengths");
+    }
+    lengths.grow(repeat, val);
+  }
+
+  if lengths[256] == 0 {
+    return Err("missing end-of-block code");
+  }
+
+  let lit = try!(Huffman::new(lengths.slice_to(nlen)));
+  let dist = try!(Huffman::new(lengths.slice_from(nlen)));
+  Ok((lit, dist))
+}
+
+fn compressed_block(r: &mut BitReader,
+                    out: &mut Vec<u8>,
+                    max_size: uint,
+                    lit: &Huffman,
+                    dist: &Huffman) -> InflateResult<()> {
+  loop {
+    let symbol = try!(lit.decode(r)) as uint;
+    if symbol != 256 && out.len() >= max_size {
+      return Err("decompressed data too large");
+    }
+    match symbol {
+      0...255 => out.push(symbol as u8),
+      256 => return Ok(()),
+      257...285 => {
+        let i = symbol - 257;
+        let len = LENGTH_BASE[i] as uint + try!(r.bits(LENGTH_EXTRA[i] as uint)) as uint;
+
+        let d = try!(dist.decode(r)) as uint;
+        if d >= MAX_DIST_CODES {
+          return Err("invalid distance code");
+        }
+        let distance = DIST_BASE[d] as uint + try!(r.bits(DIST_EXTRA[d] as uint)) as uint;
+        if distance > out.len() {
+          return Err("distance too far back");
+        }
+        if out.len() + len > max_size {
+          return Err("decompressed data too large");
+        }
+
+        // Copy byte by byte, since the source may overlap the output
+        let start = out.len() - distance;
+        for i in range(0, len) {
+          let b = out[start + i];
+          out.push(b);
+        }
+      },
+      _ => return Err("invalid literal/length code"),
+    }
+  }
+}
@@ -0,0 +1,470 @@
+use joypad;
+use sdl2;
+use turbo;
+use sdl2::controller;
+use sdl2::controller::{ControllerAxis, ControllerButton, GameController};
+use sdl2::event::Event;
+use sdl2::keycode::KeyCode;
+use std::io::{BufferedReader, File, IoResult};
+
+//
+// Input Bindings
+//
+
+// Analog stick deflection that counts as pressing a direction
+const AXIS_THRESHOLD: i16 = 16384;
+
+#[deriving(PartialEq)]
+pub enum Hotkey {
+  Pause,
+  FastForward,
+  Screenshot,
+  MovieReadOnly,
+  SwitchPlayer,     // Control the linked second Game Boy or the first
+  ToggleMute(uint), // Sound channel (0-3)
+  ToggleSolo(uint), // Sound channel (0-3)
+  Oscilloscope,
+  RunMacro(uint),   // Index of the macro
+}
+
+#[deriving(PartialEq)]
+pub enum Action {
+  Joypad(joypad::Button),
+  Turbo(joypad::Button),
+  Frontend(Hotkey),
+}
+
+// Parts of the config file
+enum Section {
+  KeyboardSection,
+  ControllerSection,
+  TurboSection,
+  MacroSection,
+}
+
+// Physical input that can be bound to an action
+#[deriving(PartialEq)]
+enum Source {
+  Key(KeyCode),
+  PadButton(ControllerButton),
+  PadAxis(ControllerAxis, bool), // Axis, positive direction
+}
+
+impl Source {
+  fn is_keyboard(&self) -> bool {
+    match *self {
+      Key(_) => true,
+      _ => false,
+    }
+  }
+}
+
+static ACTION_NAMES: &'static [(&'static str, Action)] = &[
+  ("up",              Joypad(joypad::Up)),
+  ("down",            Joypad(joypad::Down)),
+  ("left",            Joypad(joypad::Left)),
+  ("right",           Joypad(joypad::Right)),
+  ("a",               Joypad(joypad::ButtonA)),
+  ("b",               Joypad(joypad::ButtonB)),
+  ("start",           Joypad(joypad::Start)),
+  ("select",          Joypad(joypad::Select)),
+  ("turbo_a",         Turbo(joypad::ButtonA)),
+  ("turbo_b",         Turbo(joypad::ButtonB)),
+  ("pause",           Frontend(Pause)),
+  ("fast_forward",    Frontend(FastForward)),
+  ("screenshot",      Frontend(Screenshot)),
+  ("movie_read_only", Frontend(MovieReadOnly)),
+  ("switch_player",   Frontend(SwitchPlayer)),
+  ("mute_1",          Frontend(ToggleMute(0))),
+  ("mute_2",          Frontend(ToggleMute(1))),
+  ("mute_3",          Frontend(ToggleMute(2))),
+  ("mute_4",          Frontend(ToggleMute(3))),
+  ("solo_1",          Frontend(ToggleSolo(0))),
+  ("solo_2",          Frontend(ToggleSolo(1))),
+  ("solo_3",          Frontend(ToggleSolo(2))),
+  ("solo_4",          Frontend(ToggleSolo(3))),
+  ("oscilloscope",    Frontend(Oscilloscope)),
+];
+
+// Controller buttons and axes, named like in SDL's controller mappings
+static PAD_BUTTON_NAMES: &'static [(&'static str, ControllerButton)] = &[
+  ("a",             controller::ButtonA),
+  ("b",             controller::ButtonB),
+  ("x",             controller::ButtonX),
+  ("y",             controller::ButtonY),
+  ("back",          controller::ButtonBack),
+  ("guide",         controller::ButtonGuide),
+  ("start",         controller::ButtonStart),
+  ("leftstick",     controller::ButtonLeftStick),
+  ("rightstick",    controller::ButtonRightStick),
+  ("leftshoulder",  controller::ButtonLeftShoulder),
+  ("rightshoulder", controller::ButtonRightShoulder),
+  ("dpup",          controller::ButtonDPadUp),
+  ("dpdown",        controller::ButtonDPadDown),
+  ("dpleft",        controller::ButtonDPadLeft),
+  ("dpright",       controller::ButtonDPadRight),
+];
+
+static PAD_AXIS_NAMES: &'static [(&'static str, ControllerAxis)] = &[
+  ("leftx",        controller::AxisLeftX),
+  ("lefty",        controller::AxisLeftY),
+  ("rightx",       controller::AxisRightX),
+  ("righty",       controller::AxisRightY),
+  ("lefttrigger",  controller::AxisTriggerLeft),
+  ("righttrigger", controller::AxisTriggerRight),
+];
+
+fn parse_key(name: &str) -> Option<Source> {
+  match sdl2::keyboard::get_key_from_name(name) {
+    sdl2::keycode::UnknownKey => None,
+    key => Some(Key(key)),
+  }
+}
+
+// Parses a controller button ("a", "dpup") or axis direction ("leftx-",
+// "lefty+"). Triggers only have a positive direction, so the sign is optional.
+fn parse_pad(name: &str) -> Option<Source> {
+  let name = name.chars().map(|c| c.to_lowercase()).collect::<String>();
+  let name = name.as_slice();
+  match PAD_BUTTON_NAMES.iter().find(|&&(n, _)| n == name) {
+    Some(&(_, button)) => return Some(PadButton(button)),
+    None => (),
+  }
+
+  let (axis_name, positive) =
+    if name.ends_with("-") {
+      (name.slice_to(name.len() - 1), false)
+    } else if name.ends_with("+") {
+      (name.slice_to(name.len() - 1), true)
+    } else {
+      (name, true)
+    };
+  PAD_AXIS_NAMES.iter()
+    .find(|&&(n, _)| n == axis_name)
+    .map(|&(_, axis)| PadAxis(axis, positive))
+}
+
+// Splits a value of the form "name" or ["name", "name"] into names
+fn parse_names(value: &str) -> Vec<String> {
+  let value = value.trim();
+  let list =
+    if value.starts_with("[") && value.ends_with("]") {
+      value.slice(1, value.len() - 1)
+    } else {
+      value
+    };
+  list.split(',')
+    .map(|name| name.trim().trim_chars('"').to_string())
+    .filter(|name| name.len() > 0)
+    .collect()
+}
+
+// Removes a trailing comment, but not a '#' within a quoted name
+fn strip_comment(line: &str) -> &str {
+  let mut quoted = false;
+  for (i, c) in line.char_indices() {
+    match c {
+      '"' => quoted = !quoted,
+      '#' if !quoted => return line.slice_to(i),
+      _ => (),
+    }
+  }
+  line
+}
+
+pub struct Input {
+  bindings: Vec<(Source, Action)>,
+  active: Vec<bool>, // Whether each binding is currently pressed
+  controllers: Vec<GameController>,
+  turbo_rate: uint, // Frames per turbo press or release
+  macros: Vec<(String, Vec<turbo::MacroStep>)>,
+}
+
+impl Input {
+  // Creates the default bindings and opens all connected controllers
+  pub fn new() -> Input {
+    sdl2::init_subsystem(sdl2::INIT_GAME_CONTROLLER);
+
+    let mut input = Input {
+      bindings: vec!(),
+      active: vec!(),
+      controllers: vec!(),
+      turbo_rate: turbo::DEFAULT_TURBO_RATE,
+      macros: vec!(),
+    };
+    for &(key, action) in [
+      (sdl2::keycode::UpKey,     Joypad(joypad::Up)),
+      (sdl2::keycode::DownKey,   Joypad(joypad::Down)),
+      (sdl2::keycode::LeftKey,   Joypad(joypad::Left)),
+      (sdl2::keycode::RightKey,  Joypad(joypad::Right)),
+      (sdl2::keycode::ReturnKey, Joypad(joypad::Start)),
+      (sdl2::keycode::RShiftKey, Joypad(joypad::Select)),
+      (sdl2::keycode::CKey,      Joypad(joypad::ButtonA)),
+      (sdl2::keycode::XKey,      Joypad(joypad::ButtonB)),
+      (sdl2::keycode::EscapeKey, Frontend(Pause)),
+      (sdl2::keycode::SpaceKey,  Frontend(FastForward)),
+      (sdl2::keycode::TabKey,    Frontend(SwitchPlayer)),
+      (sdl2::keycode::F1Key,     Frontend(ToggleMute(0))),
+      (sdl2::keycode::F2Key,     Frontend(ToggleMute(1))),
+      (sdl2::keycode::F3Key,     Frontend(ToggleMute(2))),
+      (sdl2::keycode::F4Key,     Frontend(ToggleMute(3))),
+      (sdl2::keycode::F5Key,     Frontend(ToggleSolo(0))),
+      (sdl2::keycode::F6Key,     Frontend(ToggleSolo(1))),
+      (sdl2::keycode::F7Key,     Frontend(ToggleSolo(2))),
+      (sdl2::keycode::F8Key,     Frontend(ToggleSolo(3))),
+      (sdl2::keycode::F9Key,     Frontend(Oscilloscope)),
+      (sdl2::keycode::F10Key,    Frontend(MovieReadOnly)),
+      (sdl2::keycode::F12Key,    Frontend(Screenshot)),
+    ].iter() {
+      input.bind(Key(key), action);
+    }
+    for &(button, action) in [
+      (controller::ButtonDPadUp,    Joypad(joypad::Up)),
+      (controller::ButtonDPadDown,  Joypad(joypad::Down)),
+      (controller::ButtonDPadLeft,  Joypad(joypad::Left)),
+      (controller::ButtonDPadRight, Joypad(joypad::Right)),
+      (controller::ButtonStart,     Joypad(joypad::Start)),
+      (controller::ButtonBack,      Joypad(joypad::Select)),
+      (controller::ButtonA,         Joypad(joypad::ButtonA)),
+      (controller::ButtonB,         Joypad(joypad::ButtonB)),
+    ].iter() {
+      input.bind(PadButton(button), action);
+    }
+    input.bind(PadAxis(controller::AxisLeftX, false), Joypad(joypad::Left));
+    input.bind(PadAxis(controller::AxisLeftX, true), Joypad(joypad::Right));
+    input.bind(PadAxis(controller::AxisLeftY, false), Joypad(joypad::Up));
+    input.bind(PadAxis(controller::AxisLeftY, true), Joypad(joypad::Down));
+
+    input.open_controllers();
+    input
+  }
+
+  fn bind(&mut self, source: Source, action: Action) {
+    self.bindings.push((source, action));
+    self.active.push(false);
+  }
+
+  pub fn turbo_rate(&self) -> uint {
+    self.turbo_rate
+  }
+
+  pub fn macro_steps(&self, index: uint) -> &[turbo::MacroStep] {
+    self.macros[index].ref1().as_slice()
+  }
+
+  // Looks up an action name. Macros ("macro.NAME") may be bound before they
+  // are defined.
+  fn parse_action(&mut self, name: &str) -> Option<Action> {
+    if name.starts_with("macro.") {
+      let macro_name = name.slice_from(6);
+      return Some(Frontend(RunMacro(self.macro_index(macro_name))));
+    }
+    ACTION_NAMES.iter().find(|&&(n, _)| n == name).map(|&(_, action)| action)
+  }
+
+  fn macro_index(&mut self, name: &str) -> uint {
+    match self.macros.iter().position(|&(ref n, _)| n.as_slice() == name) {
+      Some(index) => index,
+      None => {
+        self.macros.push((name.to_string(), vec!()));
+        self.macros.len() - 1
+      },
+    }
+  }
+
+  // Loads bindings from a config file with [keyboard] and [controller]
+  // sections, e.g. `a = "C"` or `up = ["dpup", "lefty-"]`. Actions listed in
+  // a section replace their default bindings for that device, and an input
+  // bound in the file no longer triggers any other action. The [turbo]
+  // section sets the `rate` in frames, and [macros] defines named macros
+  // (see turbo::parse_macro) to be bound as `macro.NAME`.
+  pub fn load(&mut self, path: &Path) -> IoResult<()> {
+    let mut reader = BufferedReader::new(try!(File::open(path)));
+    let mut section = KeyboardSection;
+    for line in reader.lines() {
+      let line = try!(line);
+      let line = strip_comment(line.as_slice()).trim();
+      if line.len() == 0 {
+        continue;
+      }
+
+      if line.starts_with("[") {
+        match line {
+          "[keyboard]" => section = KeyboardSection,
+          "[controller]" => section = ControllerSection,
+          "[turbo]" => section = TurboSection,
+          "[macros]" => section = MacroSection,
+          _ => error!("Unknown section in {}: {:s}", path.display(), line),
+        }
+        continue;
+      }
+
+      let (name, value) =
+        match line.find('=') {
+          Some(i) => (line.slice_to(i).trim(), line.slice_from(i + 1)),
+          None => {
+            error!("Invalid line in {}: {:s}", path.display(), line);
+            continue;
+          },
+        };
+      let value = value.trim().trim_chars('"');
+
+      let keyboard =
+        match section {
+          KeyboardSection => true,
+          ControllerSection => false,
+          TurboSection => {
+            match (name, from_str::<uint>(value)) {
+              ("rate", Some(rate)) if rate > 0 => self.turbo_rate = rate,
+              _ => error!("Invalid turbo setting in {}: {:s}", path.display(), line),
+            }
+            continue;
+          },
+          MacroSection => {
+            match turbo::parse_macro(value) {
+              Some(steps) => {
+                let index = self.macro_index(name);
+                *self.macros.get_mut(index).mut1() = steps;
+              },
+              None => error!("Invalid macro in {}: {:s}", path.display(), line),
+            }
+            continue;
+          },
+        };
+
+      let action = match self.parse_action(name) {
+        Some(action) => action,
+        None => {
+          error!("Unknown action in {}: {:s}", path.display(), name);
+          continue;
+        },
+      };
+
+      self.unbind(action, keyboard);
+      for input_name in parse_names(value).iter() {
+        let source =
+          if keyboard { parse_key(input_name.as_slice()) } else { parse_pad(input_name.as_slice()) };
+        match source {
+          Some(source) => {
+            self.unbind_source(&source);
+            self.bind(source, action)
+          },
+          None => error!("Unknown input in {}: {:s}", path.display(), *input_name),
+        }
+      }
+    }
+
+    for &(ref name, ref steps) in self.macros.iter() {
+      if steps.len() == 0 {
+        error!("Macro {:s} is bound but not defined in {}", *name, path.display());
+      }
+    }
+    Ok(())
+  }
+
+  fn unbind(&mut self, action: Action, keyboard: bool) {
+    let mut i = 0;
+    while i < self.bindings.len() {
+      let matches = {
+        let (ref source, bound) = self.bindings[i];
+        bound == action && source.is_keyboard() == keyboard
+      };
+      if matches {
+        self.bindings.remove(i);
+        self.active.remove(i);
+      } else {
+        i += 1;
+      }
+    }
+  }
+
+  fn unbind_source(&mut self, source: &Source) {
+    let mut i = 0;
+    while i < self.bindings.len() {
+      let matches = {
+        let (ref bound, _) = self.bindings[i];
+        *bound == *source
+      };
+      if matches {
+        self.bindings.remove(i);
+        self.active.remove(i);
+      } else {
+        i += 1;
+      }
+    }
+  }
+
+  // Opens every connected controller, replacing those opened before
+  fn open_controllers(&mut self) {
+    self.controllers.clear();
+    let count = sdl2::joystick::num_joysticks().unwrap_or(0);
+    for index in range(0, count) {
+      if controller::is_game_controller(index) {
+        match GameController::open(index) {
+          Ok(pad) => {
+            println!("Controller connected: {}", pad.name());
+            self.controllers.push(pad);
+          },
+          Err(e) => error!("Failed to open controller: {}", e),
+        }
+      }
+    }
+  }
+
+  // Whether any binding of an action is active
+  fn is_active(&self, action: Action) -> bool {
+    self.bindings.iter().zip(self.active.iter()).any(|(&(_, a), &active)| active && a == action)
+  }
+
+  // Updates the state of all bindings matching an input. Returns the actions
+  // that have been pressed (true) or released (false). An action bound to
+  // several inputs is pressed by the first of them and released by the last.
+  fn update(&mut self, state: |&Source| -> Option<bool>) -> Vec<(Action, bool)> {
+    let mut changes = vec!();
+    for i in range(0, self.bindings.len()) {
+      let action = *self.bindings[i].ref1();
+      let new_state = state(self.bindings[i].ref0());
+      match new_state {
+        Some(pressed) if pressed != self.active[i] => {
+          let was_active = self.is_active(action);
+          *self.active.get_mut(i) = pressed;
+          if self.is_active(action) != was_active {
+            changes.push((action, pressed));
+          }
+        },
+        _ => (),
+      }
+    }
+    changes
+  }
+
+  // Translates an SDL event into actions being pressed or released
+  pub fn translate(&mut self, event: &Event) -> Vec<(Action, bool)> {
+    match *event {
+      sdl2::event::KeyDownEvent(_, _, key, _, _) =>
+        self.update(|source| match *source { Key(k) if k == key => Some(true), _ => None }),
+      sdl2::event::KeyUpEvent(_, _, key, _, _) =>
+        self.update(|source| match *source { Key(k) if k == key => Some(false), _ => None }),
+      sdl2::event::ControllerButtonDownEvent(_, _, button) =>
+        self.update(|source| match *source { PadButton(b) if b == button => Some(true), _ => None }),
+      sdl2::event::ControllerButtonUpEvent(_, _, button) =>
+        self.update(|source| match *source { PadButton(b) if b == button => Some(false), _ => None }),
+      sdl2::event::ControllerAxisMotionEvent(_, _, axis, value) =>
+        self.update(|source| match *source {
+          PadAxis(a, true) if a == axis => Some(value > AXIS_THRESHOLD),
+          PadAxis(a, false) if a == axis => Some(value < -AXIS_THRESHOLD),
+          _ => None,
+        }),
+      sdl2::event::ControllerDeviceAddedEvent(..) => {
+        self.open_controllers();
+        vec!()
+      },
+      sdl2::event::ControllerDeviceRemovedEvent(..) => {
+        // Anything held on the removed controller will never be released
+        self.open_controllers();
+        self.update(|source| match *source { PadButton(..) | PadAxis(..) => Some(false), _ => None })
+      },
+      _ => vec!(),
+    }
+  }
+}
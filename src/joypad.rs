@@ -18,6 +18,7 @@ const SELECT_BUTTON_KEYS:    u8 = 0b0010_0000;
 const INPUT_MASK:            u8 = 0b0000_1111;
 const SELECT_MASK:           u8 = 0b0011_0000;
 
+#[deriving(PartialEq, Clone)]
 pub enum Button {
   Right = 0,
   Left = 1,
@@ -29,14 +30,30 @@ pub enum Button {
   Start = 7,
 }
 
+pub static BUTTONS: [Button, ..8] = [Right, Left, Up, Down, ButtonA, ButtonB, Select, Start];
+
 pub struct Joypad {
   p1: u8,       // P1 register
   pressed: [bool, ..8],  // Button pressed state
+  interrupt: bool, // A selected input line went low since the last tick
+}
+
+pub enum Signal {
+  Interrupt
 }
 
 impl Joypad {
   pub fn new() -> Joypad {
-    Joypad { p1: 0xcf, pressed: [false, ..8] }
+    Joypad { p1: 0xcf, pressed: [false, ..8], interrupt: false }
+  }
+
+  pub fn tick(&mut self) -> Option<Signal> {
+    if self.interrupt {
+      self.interrupt = false;
+      Some(Interrupt)
+    } else {
+      None
+    }
   }
 
   pub fn set_button(&mut self, button: Button, pressed: bool) {
@@ -44,6 +61,14 @@ impl Joypad {
     self.update_input();
   }
 
+  // Sets all buttons at once, bit n set for Button n
+  pub fn set_buttons(&mut self, state: u8) {
+    for (i, pressed) in self.pressed.iter_mut().enumerate() {
+      *pressed = (state & (1 << i)) != 0;
+    }
+    self.update_input();
+  }
+
   pub fn reset(&mut self) {
     for p in self.pressed.iter_mut() {
       *p = false;
@@ -84,6 +109,11 @@ impl Joypad {
       }
     }
 
+    // Any high-to-low transition of an input line requests an interrupt
+    if (self.p1 & !input & INPUT_MASK) != 0 {
+      self.interrupt = true;
+    }
+
     self.p1 = (input & INPUT_MASK) | (self.p1 & !INPUT_MASK);
   }
 }
@@ -0,0 +1,208 @@
+use serial;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::io::{IoResult, IoError, OtherIoError};
+use std::io::net::tcp::{TcpListener, TcpStream};
+use std::io::{Acceptor, Listener};
+
+//
+// Link cable over TCP
+//
+
+// Both sides exchange a message every QUANTUM_CYCLES emulated cycles and
+// block until the peer's message for the same quantum has arrived. This keeps
+// the emulators in lockstep and makes transfers independent of host timing.
+// Replies arrive within two quanta, well before the 4096 cycles a byte takes.
+const QUANTUM_CYCLES: uint = 1024;
+
+const HANDSHAKE: &'static [u8] = b"RBLINK01";
+
+// Message flags
+const MSG_SEND: u8 = 0x01;  // Sender clocked out a byte with its internal clock
+const MSG_REPLY: u8 = 0x02; // Sender answers a byte clocked by its peer
+
+const MSG_SIZE: uint = 3; // flags, sent byte, reply byte
+
+pub struct TcpLink {
+  stream: Option<TcpStream>,
+  cycles: uint,
+  send: Option<u8>,     // Byte to send in the next message
+  reply_out: Option<u8>, // Reply to send in the next message
+  reply_in: Option<u8>,  // Reply received for our byte
+  awaiting_reply: bool,  // Our byte has been sent or queued, but not answered yet
+  clocked: Option<u8>,   // Byte clocked in by the peer
+}
+
+impl TcpLink {
+  // Waits for a peer to connect to the given address (e.g. "0.0.0.0:5115")
+  pub fn host(addr: &str) -> IoResult<TcpLink> {
+    let listener = try!(TcpListener::bind(addr));
+    let mut acceptor = try!(listener.listen());
+    let stream = try!(acceptor.accept());
+    TcpLink::start(stream)
+  }
+
+  // Connects to a peer waiting at the given address
+  pub fn connect(addr: &str) -> IoResult<TcpLink> {
+    let stream = try!(TcpStream::connect(addr));
+    TcpLink::start(stream)
+  }
+
+  fn start(mut stream: TcpStream) -> IoResult<TcpLink> {
+    try!(stream.set_nodelay(true));
+    try!(stream.write(HANDSHAKE));
+    let handshake = try!(stream.read_exact(HANDSHAKE.len()));
+    if handshake.as_slice() != HANDSHAKE {
+      return Err(IoError {
+        kind: OtherIoError,
+        desc: "peer is not a rustboy link",
+        detail: None,
+      });
+    }
+
+    Ok(TcpLink {
+      stream: Some(stream),
+      cycles: 0,
+      send: None,
+      reply_out: None,
+      reply_in: None,
+      awaiting_reply: false,
+      clocked: None,
+    })
+  }
+
+  fn exchange(&mut self, message: &[u8]) -> IoResult<Vec<u8>> {
+    let stream = self.stream.as_mut().unwrap();
+    try!(stream.write(message));
+    stream.read_exact(MSG_SIZE)
+  }
+
+  fn sync(&mut self, waiting: Option<u8>) {
+    if self.stream.is_none() {
+      return;
+    }
+
+    let mut message = [0u8, ..MSG_SIZE];
+    match self.send.take() {
+      Some(byte) => { message[0] |= MSG_SEND; message[1] = byte },
+      None => (),
+    }
+    match self.reply_out.take() {
+      Some(byte) => { message[0] |= MSG_REPLY; message[2] = byte },
+      None => (),
+    }
+
+    let received =
+      match self.exchange(&message) {
+        Ok(received) => received,
+        Err(e) => {
+          error!("Link cable disconnected: {}", e);
+          self.stream = None;
+          // Complete a pending transfer as if no peer was connected
+          if self.awaiting_reply {
+            self.awaiting_reply = false;
+            self.reply_in = Some(0xff);
+          }
+          return;
+        },
+      };
+
+    if (received[0] & MSG_REPLY) != 0 {
+      self.awaiting_reply = false;
+      self.reply_in = Some(received[2]);
+    }
+    if (received[0] & MSG_SEND) != 0 {
+      // The peer is the clock master. If we aren't waiting, it still gets an
+      // answer, but no bits are shifted into our register.
+      match waiting {
+        Some(byte) if self.clocked.is_none() => {
+          self.clocked = Some(received[1]);
+          self.reply_out = Some(byte);
+        },
+        _ => self.reply_out = Some(0xff),
+      }
+    }
+  }
+}
+
+impl serial::Peer for TcpLink {
+  fn send(&mut self, byte: u8) {
+    match self.stream {
+      Some(_) => { self.send = Some(byte); self.awaiting_reply = true },
+      None => self.reply_in = Some(0xff), // Disconnected
+    }
+  }
+
+  fn tick(&mut self, cycles: u8, waiting: Option<u8>) {
+    self.cycles += cycles as uint;
+    while self.cycles >= QUANTUM_CYCLES {
+      self.cycles -= QUANTUM_CYCLES;
+      self.sync(waiting);
+    }
+  }
+
+  fn take_reply(&mut self) -> Option<u8> {
+    self.reply_in.take()
+  }
+
+  fn take_clocked(&mut self) -> Option<u8> {
+    self.clocked.take()
+  }
+}
+
+
+//
+// Link cable within one process
+//
+
+// Bytes in flight from one side of the cable to the other
+struct Wire {
+  sent: Option<u8>,
+  reply: Option<u8>,
+}
+
+// One end of an in-memory cable. Both Game Boys must be stepped in lockstep
+// from the same thread; bytes are then delivered on the peer's next step.
+pub struct LocalLink {
+  outgoing: Rc<RefCell<Wire>>,
+  incoming: Rc<RefCell<Wire>>,
+  clocked: Option<u8>,
+}
+
+// Creates both ends of a cable
+pub fn local_pair() -> (LocalLink, LocalLink) {
+  let a = Rc::new(RefCell::new(Wire { sent: None, reply: None }));
+  let b = Rc::new(RefCell::new(Wire { sent: None, reply: None }));
+  (LocalLink { outgoing: a.clone(), incoming: b.clone(), clocked: None },
+   LocalLink { outgoing: b, incoming: a, clocked: None })
+}
+
+impl serial::Peer for LocalLink {
+  fn send(&mut self, byte: u8) {
+    self.outgoing.borrow_mut().sent = Some(byte);
+  }
+
+  fn tick(&mut self, _cycles: u8, waiting: Option<u8>) {
+    let sent = self.incoming.borrow_mut().sent.take();
+    match sent {
+      Some(byte) => {
+        // Same rules as TcpLink: only a waiting side shifts the byte in
+        let reply =
+          match waiting {
+            Some(reply) if self.clocked.is_none() => { self.clocked = Some(byte); reply },
+            _ => 0xff,
+          };
+        self.outgoing.borrow_mut().reply = Some(reply);
+      },
+      None => (),
+    }
+  }
+
+  fn take_reply(&mut self) -> Option<u8> {
+    self.incoming.borrow_mut().reply.take()
+  }
+
+  fn take_clocked(&mut self) -> Option<u8> {
+    self.clocked.take()
+  }
+}
@@ -3,23 +3,45 @@
 #[phase(plugin, link)]
 extern crate log;
 
+extern crate getopts;
 extern crate sdl2;
+extern crate time;
 
 use mem::Mem;
-use std::io::stdio;
+use std::cell::Cell;
+use std::rc::Rc;
 
+mod archive;
+mod audio;
 mod cartridge;
+mod cheats;
 mod cpu;
+mod crc32;
 mod debug;
 mod disasm;
+mod gbs;
+mod header;
+mod inflate;
+mod input;
 mod interrupt;
 mod joypad;
+mod link;
 mod mem;
+mod movie;
+mod patch;
+mod png;
+mod printer;
 mod ram;
+mod rtc;
 mod serial;
 mod sound;
 mod timer;
+mod turbo;
+mod vgm;
 mod video;
+mod wav;
+
+const DEFAULT_GBS_SECONDS: uint = 150;
 
 //
 // Memory Map
@@ -38,7 +60,7 @@ impl Mem for Dummy {
 }
 
 struct MemMap<'a> {
-  cart: Box<cartridge::Cartridge>,
+  cart: Box<cartridge::Slot + 'a>,
   wram: ram::WorkRam,
   timer: timer::Timer,
   intr: interrupt::InterruptCtrl,
@@ -46,10 +68,39 @@ struct MemMap<'a> {
   video: video::Video,
   serial: serial::SerialIO<'a>,
   joypad: joypad::Joypad,
+  cheats: cheats::Cheats,
+  vgm: Option<vgm::VgmWriter>,
   dummy: Dummy,
+
+  // Timer synchronization within the current instruction
+  in_step: bool,
+  access_cycles: u8, // Cycles up to and including the latest memory access
+  timer_cycles: u8,  // Cycles already emulated by the timer
+
+  blank_cycles: uint, // Cycles in STOP mode or with the LCD off since the last reported frame
 }
 
 impl<'a> MemMap<'a> {
+  fn new(cart: Box<cartridge::Slot + 'a>, cheats: cheats::Cheats) -> MemMap<'a> {
+    MemMap {
+      cart: cart,
+      wram: ram::WorkRam::new(),
+      timer: timer::Timer::new(),
+      intr: interrupt::InterruptCtrl::new(),
+      sound: sound::Sound::new(),
+      video: video::Video::new(),
+      serial: serial::SerialIO::new(),
+      joypad: joypad::Joypad::new(),
+      cheats: cheats,
+      vgm: None,
+      dummy: Dummy,
+      in_step: false,
+      access_cycles: 0,
+      timer_cycles: 0,
+      blank_cycles: 0,
+    }
+  }
+
   fn mem_from_addr(&mut self, addr: u16) -> &mut Mem {
     match addr {
       0x0000...0x7fff | // ROM banks
@@ -72,17 +123,105 @@ impl<'a> MemMap<'a> {
   }
 }
 
+impl<'a> MemMap<'a> {
+  fn tick_timer(&mut self, cycles: u8) {
+    match self.timer.tick(cycles) {
+      Some(timer::TIMAOverflow) => self.intr.irq(interrupt::IRQ_TIMER),
+      None => (),
+    }
+    self.timer_cycles += cycles;
+  }
+
+  // Every memory access takes one machine cycle. The timer is brought up to
+  // date before its registers are accessed, so the access sees the timer as
+  // it is in the middle of the instruction rather than at its start.
+  //
+  // This is an approximation: internal cycles without a memory access (as in
+  // CALL, PUSH, RET cc or ADD HL) aren't counted, so an access following them
+  // sees the timer up to a few machine cycles early. The instructions games
+  // use on the timer registers (LDH, LD (C)/(HL)/(nn)) have no internal
+  // cycles before their access and are exact. The rest of the instruction's
+  // cycles are always caught up in end_step.
+  fn count_access(&mut self, addr: u16) {
+    if !self.in_step {
+      return;
+    }
+    self.access_cycles += 4;
+    if addr >= 0xff04 && addr <= 0xff07 && self.access_cycles > self.timer_cycles {
+      let cycles = self.access_cycles - self.timer_cycles;
+      self.tick_timer(cycles);
+    }
+  }
+
+  fn begin_step(&mut self) {
+    self.in_step = true;
+    self.access_cycles = 0;
+    self.timer_cycles = 0;
+  }
+
+  // Emulates the timer for the rest of an instruction's cycles
+  fn end_step(&mut self, cycles: u8) {
+    self.in_step = false;
+    if cycles > self.timer_cycles {
+      let remaining = cycles - self.timer_cycles;
+      self.tick_timer(remaining);
+    }
+  }
+
+  // Counts cycles without V-Blank, while in STOP mode or with the LCD off.
+  // Returns whether a frame's worth has passed, so frames are still reported
+  // at the usual rate and everything advancing per frame keeps going.
+  fn count_blank_cycles(&mut self, cycles: u8) -> bool {
+    self.blank_cycles += cycles as uint;
+    if self.blank_cycles >= video::SCREEN_REFRESH_CYCLES {
+      self.blank_cycles -= video::SCREEN_REFRESH_CYCLES;
+      return true;
+    }
+    false
+  }
+
+  // Writes GameShark cheat values, done once per frame at V-Blank
+  fn apply_cheats(&mut self) {
+    for &(addr, val) in self.cheats.ram_writes().iter() {
+      self.storeb(addr, val);
+    }
+  }
+}
+
 impl<'a> Mem for MemMap<'a> {
   fn loadb(&mut self, addr: u16) -> u8 {
-    self.mem_from_addr(addr).loadb(addr)
+    self.count_access(addr);
+    let val = self.mem_from_addr(addr).loadb(addr);
+    match addr {
+      0x0000...0x7fff => self.cheats.patch_rom(addr, val), // Game Genie codes
+      _ => val,
+    }
   }
 
   fn storeb(&mut self, addr: u16, val: u8) {
+    self.count_access(addr);
+    if addr >= 0xff10 && addr <= 0xff3f {
+      match self.vgm {
+        Some(ref mut vgm) => vgm.write(addr, val),
+        None => (),
+      }
+    }
     self.mem_from_addr(addr).storeb(addr, val)
   }
 }
 
 
+impl<'a> debug::DebugTarget for MemMap<'a> {
+  fn cheats(&mut self) -> &mut cheats::Cheats {
+    &mut self.cheats
+  }
+
+  fn sound(&mut self) -> &mut sound::Sound {
+    &mut self.sound
+  }
+}
+
+
 //
 // Video Output
 //
@@ -93,12 +232,13 @@ struct VideoOut {
 }
 
 impl VideoOut {
-  fn new(scale: int) -> VideoOut {
+  // Opens a window showing the given number of screens side by side
+  fn new(scale: int, screens: uint) -> VideoOut {
     use sdl2::render::Renderer;
 
     sdl2::init(sdl2::INIT_VIDEO);
 
-    let window_width = video::SCREEN_WIDTH as int * scale;
+    let window_width = (video::SCREEN_WIDTH * screens) as int * scale;
     let window_height = video::SCREEN_HEIGHT as int * scale;
 
     let renderer = match Renderer::new_with_window(window_width,
@@ -107,6 +247,10 @@ impl VideoOut {
       Ok(renderer) => renderer,
       Err(err) => panic!("Failed to create renderer: {}", err)
     };
+    if screens > 1 {
+      let _ = renderer.set_logical_size((video::SCREEN_WIDTH * screens) as int,
+                                        video::SCREEN_HEIGHT as int);
+    }
 
     let texture = match renderer.create_texture(sdl2::pixels::ARGB8888,
                                                 sdl2::render::AccessStreaming,
@@ -119,9 +263,18 @@ impl VideoOut {
     VideoOut { renderer: box renderer, texture: box texture }
   }
 
-  fn blit_and_present(&self, pixels: &[u8]) {
-    self.texture.update(None, pixels, (video::SCREEN_WIDTH * 4) as int);
-    self.renderer.copy(&*self.texture, None, None);
+  fn blit_and_present(&self, screens: &[&[u8]]) {
+    for (i, pixels) in screens.iter().enumerate() {
+      let dst =
+        if screens.len() == 1 {
+          None
+        } else {
+          Some(sdl2::rect::Rect::new((i * video::SCREEN_WIDTH) as i32, 0,
+                                     video::SCREEN_WIDTH as i32, video::SCREEN_HEIGHT as i32))
+        };
+      self.texture.update(None, *pixels, (video::SCREEN_WIDTH * 4) as int);
+      self.renderer.copy(&*self.texture, None, dst);
+    }
     self.renderer.present();
   }
 
@@ -131,17 +284,157 @@ impl VideoOut {
 }
 
 
-fn keymap(code: sdl2::keycode::KeyCode) -> Option<joypad::Button> {
-  match code {
-    sdl2::keycode::UpKey     => Some(joypad::Up),
-    sdl2::keycode::DownKey   => Some(joypad::Down),
-    sdl2::keycode::LeftKey   => Some(joypad::Left),
-    sdl2::keycode::RightKey  => Some(joypad::Right),
-    sdl2::keycode::ReturnKey => Some(joypad::Start),
-    sdl2::keycode::RShiftKey => Some(joypad::Select),
-    sdl2::keycode::CKey      => Some(joypad::ButtonA),
-    sdl2::keycode::XKey      => Some(joypad::ButtonB),
-    _ => None,
+//
+// Oscilloscope
+//
+
+const SCOPE_WIDTH: uint = 512;
+const SCOPE_CHANNEL_HEIGHT: uint = 64;
+
+// Debug window tracing the output of each sound channel
+struct ScopeOut {
+  renderer: Box<sdl2::render::Renderer<sdl2::video::Window>>,
+}
+
+impl ScopeOut {
+  fn new() -> ScopeOut {
+    use sdl2::render::Renderer;
+
+    let renderer = match Renderer::new_with_window(SCOPE_WIDTH as int,
+                                                   (SCOPE_CHANNEL_HEIGHT * sound::CHANNEL_COUNT) as int,
+                                                   sdl2::video::SHOWN) {
+      Ok(renderer) => renderer,
+      Err(err) => panic!("Failed to create renderer: {}", err)
+    };
+    renderer.get_parent().set_title("Rustboy - Channels");
+
+    ScopeOut { renderer: box renderer }
+  }
+
+  fn draw(&self, sound: &sound::Sound) {
+    use sdl2::rect::Point;
+
+    self.renderer.set_draw_color(sdl2::pixels::RGB(0, 0, 0));
+    self.renderer.clear();
+
+    for channel in range(0u, sound::CHANNEL_COUNT) {
+      let color =
+        if sound.solo().map_or(sound.is_muted(channel), |solo| solo != channel) {
+          sdl2::pixels::RGB(0x40, 0x40, 0x40)
+        } else {
+          sdl2::pixels::RGB(0x40, 0xff, 0x40)
+        };
+      self.renderer.set_draw_color(color);
+
+      let middle = (channel * SCOPE_CHANNEL_HEIGHT + SCOPE_CHANNEL_HEIGHT / 2) as i32;
+      let tap = match sound.tap(channel) {
+        Some(tap) if tap.len() > 0 => tap,
+        // Nothing captured yet, show a flat trace
+        _ => {
+          let _ = self.renderer.draw_line(Point::new(0, middle),
+                                          Point::new(SCOPE_WIDTH as i32 - 1, middle));
+          continue;
+        },
+      };
+
+      // Show the latest samples, scaled so -15..15 fills the channel's row
+      let skip = if tap.len() > SCOPE_WIDTH { tap.len() - SCOPE_WIDTH } else { 0 };
+      let mut last: Option<Point> = None;
+      for (x, &sample) in tap.iter().skip(skip).enumerate() {
+        let y = middle - sample as i32 * (SCOPE_CHANNEL_HEIGHT as i32 / 2 - 2) / 15;
+        let point = Point::new(x as i32, y);
+        match last {
+          Some(prev) => self.renderer.draw_line(prev, point),
+          None => self.renderer.draw_point(point),
+        };
+        last = Some(point);
+      }
+    }
+
+    self.renderer.present();
+  }
+}
+
+
+//
+// Rumble Indicator
+//
+
+struct RumbleIndicator {
+  active: Rc<Cell<bool>>,
+}
+
+impl cartridge::RumbleHandler for RumbleIndicator {
+  fn set_rumble(&mut self, on: bool) {
+    self.active.set(on);
+  }
+}
+
+fn window_title(fps: u64, rumble: bool) -> String {
+  let mut title = format!("Rustboy - {} fps", fps);
+  if rumble {
+    title.push_str(" [RUMBLE]");
+  }
+  title
+}
+
+// Base path for files derived from the ROM, e.g. foo.gb -> foo-screenshot
+fn output_base(rom: &str, suffix: &str) -> Path {
+  let rom_path = Path::new(rom);
+  let stem = rom_path.filestem_str().unwrap_or("rustboy");
+  rom_path.with_filename(format!("{:s}-{:s}", stem, suffix))
+}
+
+// Saves a screen (BGRA) as the next numbered PNG file
+fn save_screenshot(screen: &[u8], base: &Path) {
+  let mut rgb = Vec::with_capacity(video::SCREEN_WIDTH * video::SCREEN_HEIGHT * 3);
+  for pixel in screen.chunks(4) {
+    rgb.push(pixel[2]);
+    rgb.push(pixel[1]);
+    rgb.push(pixel[0]);
+  }
+  let path = png::numbered_path(base);
+  match png::write_rgb(&path, video::SCREEN_WIDTH, video::SCREEN_HEIGHT, rgb.as_slice()) {
+    Ok(()) => println!("Saved screenshot {}", path.display()),
+    Err(e) => error!("Failed to save screenshot: {}", e),
+  }
+}
+
+
+// Joypad driven by the keyboard and controllers: the first Game Boy's, or the
+// linked second one's
+fn controlled_joypad<'a, 'b>(cpu: &'b mut cpu::Cpu<MemMap<'a>>,
+                             second: &'b mut Option<cpu::Cpu<MemMap<'a>>>,
+                             control_second: bool) -> &'b mut joypad::Joypad {
+  match *second {
+    Some(ref mut second) if control_second => &mut second.mem.joypad,
+    _ => &mut cpu.mem.joypad,
+  }
+}
+
+// Presses or releases a button on the controlled joypad. The first Game Boy's
+// input goes through the movie while one is active and is applied at V-Blank.
+fn press_button<'a>(cpu: &mut cpu::Cpu<MemMap<'a>>,
+                    second: &mut Option<cpu::Cpu<MemMap<'a>>>,
+                    movie: &mut Option<movie::Movie>,
+                    control_second: bool,
+                    button: joypad::Button,
+                    pressed: bool) {
+  match *movie {
+    Some(ref mut movie) if !(control_second && second.is_some()) => movie.set_button(button, pressed),
+    _ => controlled_joypad(cpu, second, control_second).set_button(button, pressed),
+  }
+}
+
+// Saves a movie if frames have been recorded
+fn save_movie(movie: &Option<movie::Movie>) {
+  match *movie {
+    Some(ref movie) if movie.is_modified() => match (movie.save(), movie.path()) {
+      (Ok(()), Some(path)) => println!("Saved movie with {:u} frames to {}", movie.len(), path.display()),
+      (Err(e), _) => error!("Failed to save movie: {}", e),
+      _ => (),
+    },
+    _ => (),
   }
 }
 
@@ -154,28 +447,567 @@ enum State {
   Done,
 }
 
+// Executes one instruction and advances all components accordingly. Returns
+// the elapsed cycles and whether a new frame has been completed (V-Blank).
+fn step<'a>(cpu: &mut cpu::Cpu<MemMap<'a>>) -> (u8, bool) {
+  match cpu.mem.vgm {
+    Some(ref mut vgm) => vgm.set_cycles(cpu.cycles),
+    None => (),
+  }
+
+  cpu.mem.begin_step();
+  let cycles = cpu.step();
+
+  cpu.mem.cart.tick(cycles);
+
+  match cpu.mem.joypad.tick() {
+    Some(joypad::Interrupt) => cpu.mem.intr.irq(interrupt::IRQ_JOYPAD),
+    None => (),
+  }
+
+  if cpu.is_stopped() {
+    // The timer, sound and LCD are stopped as well. The frontend keeps
+    // polling for the wake-up press on the reported frames.
+    cpu.mem.end_step(0);
+    let new_frame = cpu.mem.count_blank_cycles(cycles);
+    return (cycles, new_frame);
+  }
+  cpu.mem.end_step(cycles);
+
+  match cpu.mem.serial.tick(cycles) {
+    Some(serial::TransferComplete) => cpu.mem.intr.irq(interrupt::IRQ_SERIAL),
+    None => (),
+  }
+
+  let div = cpu.mem.timer.div();
+  cpu.mem.sound.tick(cycles, div);
+
+  let mut new_frame = false;
+  let video_signals = cpu.mem.video.tick(cycles);
+  for signal in video_signals.iter() {
+    match *signal {
+      video::DMA(base) => {
+        // Do DMA transfer instantaneously
+        let base_addr = base as u16 << 8;
+        for offset in range(0x00u16, 0xa0u16) {
+          let val = cpu.mem.loadb(base_addr + offset);
+          cpu.mem.storeb(0xfe00 + offset, val);
+        }
+      },
+      video::VBlank => {
+        cpu.mem.intr.irq(interrupt::IRQ_VBLANK);
+        cpu.mem.apply_cheats();
+        new_frame = true;
+      }
+      video::LCD    => cpu.mem.intr.irq(interrupt::IRQ_LCD),
+    }
+  }
+  if !cpu.mem.video.is_enabled() {
+    new_frame = cpu.mem.count_blank_cycles(cycles);
+  } else {
+    // V-Blank timing has resumed, so the next blank period starts afresh
+    cpu.mem.blank_cycles = 0;
+  }
+
+  (cycles, new_frame)
+}
+
+// Persists battery-backed RAM and finishes logs before exiting
+fn shutdown<'a>(cpu: &mut cpu::Cpu<MemMap<'a>>) {
+  match cpu.mem.cart.save_ram() {
+    Err(e) => error!("Failed to save cartridge RAM: {}", e),
+    _ => (),
+  }
+  match cpu.mem.vgm {
+    Some(ref mut vgm) => match vgm.finish(cpu.cycles) {
+      Err(e) => error!("Failed to write VGM log: {}", e),
+      _ => (),
+    },
+    None => (),
+  }
+}
+
+// Writes out the samples of a completed frame, stopping the recording if
+// that fails
+fn flush_recording(recorder: &mut Option<wav::AudioRecorder>) {
+  let record_error =
+    match *recorder {
+      Some(ref mut rec) => rec.flush().err(),
+      None => None,
+    };
+  match record_error {
+    Some(e) => { error!("Failed to write audio: {}", e); *recorder = None },
+    None => (),
+  }
+}
+
+// Writes the rest of a WAV recording and completes the file
+fn finish_recording(recorder: &mut Option<wav::AudioRecorder>) {
+  match *recorder {
+    Some(ref mut rec) => match rec.finish() {
+      Err(e) => error!("Failed to write audio: {}", e),
+      _ => (),
+    },
+    None => (),
+  }
+}
+
+// Steps a second Game Boy, linked to the first in this process, until it has
+// caught up with the first's cycle count. Returns whether it finished a frame.
+fn catch_up<'a>(cycles: u64, second: &mut cpu::Cpu<MemMap<'a>>) -> bool {
+  let mut new_frame = false;
+  while second.cycles < cycles {
+    let (_, frame) = step(second);
+    new_frame = new_frame || frame;
+  }
+  new_frame
+}
+
+
+//
+// Headless Driver
+//
+
+// One Game Boy, or two linked by an in-memory cable, run without video,
+// audio device or debugger. Test scripts drive it frame by frame and inspect
+// either Game Boy, numbered 0 (first) and 1 (linked second).
+struct Headless<'a> {
+  first: cpu::Cpu<MemMap<'a>>,
+  second: Option<cpu::Cpu<MemMap<'a>>>,
+}
+
+impl<'a> Headless<'a> {
+  fn new(first: cpu::Cpu<MemMap<'a>>, second: Option<cpu::Cpu<MemMap<'a>>>) -> Headless<'a> {
+    Headless { first: first, second: second }
+  }
+
+  fn has_gameboy(&self, which: uint) -> bool {
+    which == 0 || (which == 1 && self.second.is_some())
+  }
+
+  fn gameboy(&mut self, which: uint) -> &mut cpu::Cpu<MemMap<'a>> {
+    match which {
+      0 => &mut self.first,
+      1 => match self.second {
+        Some(ref mut second) => second,
+        None => panic!("no linked second Game Boy"),
+      },
+      _ => panic!("invalid Game Boy number {:u}", which),
+    }
+  }
+
+  // Executes one instruction on the first Game Boy and brings the second up
+  // to the same cycle count. Returns the first's elapsed cycles and whether
+  // it completed a frame.
+  fn step(&mut self) -> (u8, bool) {
+    let (cycles, new_frame) = step(&mut self.first);
+    match self.second {
+      Some(ref mut second) => { catch_up(self.first.cycles, second); },
+      None => (),
+    }
+    (cycles, new_frame)
+  }
+
+  // Runs until the first Game Boy completes a frame, recording its audio
+  fn step_frame(&mut self, recorder: &mut Option<wav::AudioRecorder>) {
+    loop {
+      let (cycles, new_frame) = self.step();
+      match *recorder {
+        Some(ref mut rec) => rec.push(self.first.mem.sound.output(), cycles),
+        None => (),
+      }
+      if new_frame {
+        break;
+      }
+    }
+  }
+
+  fn set_button(&mut self, which: uint, button: joypad::Button, pressed: bool) {
+    self.gameboy(which).mem.joypad.set_button(button, pressed);
+  }
+
+  // Screen contents (BGRA) as of the last completed frame
+  fn screen(&mut self, which: uint) -> &[u8] {
+    self.gameboy(which).mem.video.screen.as_slice()
+  }
+
+  // Memory as seen by the CPU, including I/O registers
+  fn loadb(&mut self, which: uint, addr: u16) -> u8 {
+    self.gameboy(which).mem.loadb(addr)
+  }
+
+  fn storeb(&mut self, which: uint, addr: u16, val: u8) {
+    self.gameboy(which).mem.storeb(addr, val);
+  }
+
+  fn shutdown(&mut self) {
+    shutdown(&mut self.first);
+    match self.second {
+      Some(ref mut second) => shutdown(second),
+      None => (),
+    }
+  }
+}
+
+// Runs headless for the given number of frames, or forever
+fn run_headless<'a>(headless: &mut Headless<'a>,
+                    movie: &mut Option<movie::Movie>,
+                    max_frames: Option<uint>,
+                    recorder: &mut Option<wav::AudioRecorder>) {
+  let mut frames = 0u;
+  while max_frames.map_or(true, |max| frames < max) {
+    headless.step_frame(recorder);
+    frames += 1;
+    match *movie {
+      Some(ref mut movie) => headless.first.mem.joypad.set_buttons(movie.frame()),
+      None => (),
+    }
+    flush_recording(recorder);
+  }
+}
+
+// Runs headless driven by commands read from standard input, one per line,
+// until "quit" or the end of input. Game Boys are numbered as in Headless.
+//
+//   frame [N]           run N frames (default: 1)
+//   press GB BUTTON     hold a button (up, down, left, right, a, b, start, select)
+//   release GB BUTTON   release a button
+//   peek GB ADDR        print the byte at ADDR as two hex digits
+//   poke GB ADDR VAL    write VAL to ADDR
+//   screenshot GB       save the screen as the next numbered PNG file
+//
+// Numbers are decimal, or hex prefixed with '$'. Invalid commands are
+// reported on stderr and skipped.
+fn run_script<'a>(headless: &mut Headless<'a>,
+                  screenshot_base: &Path,
+                  recorder: &mut Option<wav::AudioRecorder>) {
+  let mut stdin = std::io::BufferedReader::new(std::io::stdio::stdin());
+  for line in stdin.lines() {
+    let line = match line {
+      Ok(line) => line,
+      Err(_) => break,
+    };
+    let words = line.as_slice().words().collect::<Vec<&str>>();
+    if words.len() == 0 {
+      continue;
+    }
+    let gameboy_commands = ["press", "release", "peek", "poke", "screenshot"];
+    let mut which = 0;
+    if words.len() > 1 && gameboy_commands.iter().any(|c| *c == words[0]) {
+      match from_str::<uint>(words[1]) {
+        Some(n) if headless.has_gameboy(n) => which = n,
+        _ => { error!("Invalid Game Boy number: {:s}", words[1]); continue; },
+      }
+    }
+
+    match (words[0], words.len()) {
+      ("quit", 1) => break,
+      ("frame", 1) => {
+        headless.step_frame(recorder);
+        flush_recording(recorder);
+      },
+      ("frame", 2) => match from_str::<uint>(words[1]) {
+        Some(n) => for _ in range(0, n) {
+          headless.step_frame(recorder);
+          flush_recording(recorder);
+        },
+        None => error!("Invalid frame count: {:s}", words[1]),
+      },
+      ("press", 3) | ("release", 3) => match turbo::parse_button(words[2]) {
+        Some(button) => headless.set_button(which, button, words[0] == "press"),
+        None => error!("Unknown button: {:s}", words[2]),
+      },
+      ("peek", 3) => match debug::parse_addr(words[2]) {
+        Some(addr) => {
+          println!("{:02X}", headless.loadb(which, addr));
+          std::io::stdio::flush();
+        },
+        None => error!("Invalid address: {:s}", words[2]),
+      },
+      ("poke", 4) => match (debug::parse_addr(words[2]), debug::parse_addr(words[3])) {
+        (Some(addr), Some(val)) if val <= 0xff => headless.storeb(which, addr, val as u8),
+        _ => error!("Invalid address or value: {:s} {:s}", words[2], words[3]),
+      },
+      ("screenshot", 2) => save_screenshot(headless.screen(which), screenshot_base),
+      _ => error!("Unknown command: {:s}", line.as_slice().trim()),
+    }
+  }
+}
+
+// Plays a track of a GBS file to the audio device (paced by its buffer) or,
+// if recording, as fast as possible to a WAV file.
+fn play_gbs(path: &Path,
+            track: Option<u8>,
+            seconds: uint,
+            mut audio_out: Option<audio::AudioOut>,
+            mut recorder: Option<wav::AudioRecorder>,
+            vgm: Option<vgm::VgmWriter>) {
+  let rom = match gbs::GbsRom::from_path(path) {
+    Ok(rom) => box rom,
+    Err(e) => {
+      error!("Failed to load {}: {}", path.display(), e);
+      std::os::set_exit_status(1);
+      return;
+    }
+  };
+
+  println!("Title:     {:s}", rom.header.title);
+  println!("Author:    {:s}", rom.header.author);
+  println!("Copyright: {:s}", rom.header.copyright);
+
+  let track = track.unwrap_or(rom.header.first_song);
+  if track < 1 || track > rom.header.song_count {
+    println!("Invalid track {:u}, the file has {:u} songs", track, rom.header.song_count);
+    std::os::set_exit_status(1);
+    return;
+  }
+  println!("Playing track {:u}/{:u} for {:u} seconds", track, rom.header.song_count, seconds);
+
+  let header = rom.header.clone();
+  let mut cpu = cpu::Cpu::new(MemMap::new(rom as Box<cartridge::Slot>, cheats::Cheats::new()));
+  cpu.mem.vgm = vgm;
+  gbs::init_song(&mut cpu, &header, track - 1);
+
+  let end_cycles = (seconds * cpu::CYCLES_PER_SEC) as u64;
+  while cpu.cycles < end_cycles {
+    let (cycles, new_frame) = step(&mut cpu);
+
+    let output = cpu.mem.sound.output();
+    match audio_out {
+      Some(ref mut audio) => audio.push(output, cycles),
+      None => (),
+    }
+    match recorder {
+      Some(ref mut rec) => rec.push(output, cycles),
+      None => (),
+    }
+
+    if new_frame {
+      match audio_out {
+        Some(ref mut audio) => { audio.flush(); audio.wait() },
+        None => (),
+      }
+      match recorder {
+        Some(ref mut rec) => match rec.flush() {
+          Err(e) => { println!("Failed to write audio: {}", e); break },
+          _ => (),
+        },
+        None => (),
+      }
+    }
+  }
+
+  finish_recording(&mut recorder);
+  shutdown(&mut cpu);
+}
+
+fn yes_no(b: bool) -> &'static str {
+  if b { "yes" } else { "no" }
+}
+
+fn ok_bad(b: bool) -> &'static str {
+  if b { "OK" } else { "BAD" }
+}
+
+// Prints every header field, reporting values the emulator can't handle
+// instead of refusing the ROM, so that bad dumps can still be inspected.
+fn print_info(h: &header::Header, rom_len: uint) {
+  let title_len = h.title.iter().position(|&b| b == 0).unwrap_or(h.title.len());
+  let ascii = h.title.iter().all(|&b| b < 0x80);
+
+  println!("Title:             {}{:s}", String::from_utf8_lossy(h.title.slice_to(title_len)),
+           if ascii { "" } else { " (BAD: not ASCII)" });
+  println!("Manufacturer code: {}", String::from_utf8_lossy(h.manufacturer_code.as_slice()));
+  println!("CGB flag:          0x{:02X} (supported: {:s}, required: {:s})",
+           h.cgb_flag, yes_no(h.supports_cgb()), yes_no(h.requires_cgb()));
+  println!("SGB flag:          0x{:02X} (supported: {:s})", h.sgb_flag, yes_no(h.supports_sgb()));
+  if h.uses_new_licensee_code() {
+    println!("Licensee code:     {} (new)", String::from_utf8_lossy(h.new_licensee_code.as_slice()));
+  } else {
+    println!("Licensee code:     0x{:02X} (old)", h.old_licensee_code);
+  }
+  println!("Cartridge type:    0x{:02X} ({:s})", h.cartridge_type,
+           if cartridge::is_supported_type(h.cartridge_type) { "supported" } else { "BAD: unsupported" });
+  match cartridge::rom_bank_count(h.rom_size) {
+    Some(banks) if rom_len < banks * cartridge::ROM_BANK_SIZE =>
+      println!("ROM size:          0x{:02X} ({:u} banks, BAD: file has {:u} bytes)", h.rom_size, banks, rom_len),
+    Some(banks) => println!("ROM size:          0x{:02X} ({:u} banks)", h.rom_size, banks),
+    None => println!("ROM size:          0x{:02X} (BAD: unknown size code)", h.rom_size),
+  }
+  match cartridge::ram_bytes(h.cartridge_type, h.ram_size) {
+    Some(bytes) => println!("RAM size:          0x{:02X} ({:u} bytes)", h.ram_size, bytes),
+    None => println!("RAM size:          0x{:02X} (BAD: unknown size code)", h.ram_size),
+  }
+  println!("Destination:       0x{:02X} ({:s})",
+           h.destination, if h.is_japanese() { "Japanese" } else { "non-Japanese" });
+  println!("Mask ROM version:  0x{:02X}", h.version);
+  println!("Nintendo logo:     {:s}", ok_bad(h.logo_valid()));
+  println!("Header checksum:   0x{:02X} (computed 0x{:02X}) {:s}",
+           h.header_checksum, h.computed_header_checksum(), ok_bad(h.header_checksum_valid()));
+  println!("Global checksum:   0x{:04X} (computed 0x{:04X}) {:s}",
+           h.global_checksum, h.computed_global_checksum(), ok_bad(h.global_checksum_valid()));
+}
+
+fn print_usage(program: &str, opts: &[getopts::OptGroup]) {
+  let brief = format!("Usage: {:s} [options] rom.gb", program);
+  print!("{}", getopts::usage(brief.as_slice(), opts));
+}
+
 fn main() {
   let args = std::os::args();
-  if args.len() != 2 && !(args.len() == 3 && args[1] == "-d".to_string()) {
-    println!("Usage: {:s} [-d] rom.gb", args[0]);
+  let program = args[0].clone();
+
+  let opts = [
+    getopts::optflag("d", "", "disassemble the ROM and exit"),
+    getopts::optflag("", "info", "print the cartridge header and exit"),
+    getopts::optopt("", "patch", "apply an IPS, UPS or BPS patch to the ROM", "FILE"),
+    getopts::optflag("", "no-audio", "disable audio output"),
+    getopts::optflag("", "audio-sync", "pace emulation by the audio device instead of wall-clock time"),
+    getopts::optopt("", "record-audio", "record audio output to a WAV file", "FILE"),
+    getopts::optopt("", "sample-rate", "sample rate for recorded audio (default: 44100)", "HZ"),
+    getopts::optopt("", "log-vgm", "log sound register writes to a VGM file", "FILE"),
+    getopts::optflag("", "headless", "run without video, audio or debugger"),
+    getopts::optopt("", "frames", "exit after emulating this many frames (headless mode)", "N"),
+    getopts::optflag("", "script", "read commands from standard input (headless mode)"),
+    getopts::optopt("", "link-host", "wait for a link cable peer on this address", "ADDR:PORT"),
+    getopts::optopt("", "link-connect", "connect the link cable to a waiting peer", "ADDR:PORT"),
+    getopts::optopt("", "record", "record input to a movie file", "FILE"),
+    getopts::optopt("", "play", "play back a movie file (branch off into the --record file)", "FILE"),
+    getopts::optopt("", "config", "load key and controller bindings (default: rustboy.toml)", "FILE"),
+    getopts::optflag("", "printer", "connect a Game Boy Printer, saving printouts as PNG files"),
+    getopts::optopt("", "link-local", "link to a second Game Boy running this ROM (saved as ROM-2.sav)", "ROM"),
+    getopts::optopt("", "track", "GBS track to play (default: first song of the file)", "N"),
+    getopts::optopt("", "length", "GBS playback length in seconds (default: 150)", "SECONDS"),
+    getopts::optflag("h", "help", "print this help"),
+  ];
+  let matches = match getopts::getopts(args.tail(), opts) {
+    Ok(m) => m,
+    Err(f) => {
+      println!("{}", f);
+      print_usage(program.as_slice(), opts);
+      std::os::set_exit_status(1);
+      return;
+    }
+  };
+  if matches.opt_present("h") || matches.free.len() != 1 {
+    print_usage(program.as_slice(), opts);
     return;
   }
 
-  let mut disassemble = false;
-  let path =
-    if args.len() == 2 {
-      &args[1]
-    } else {
-      disassemble = true;
-      &args[2]
+  let path = &matches.free[0];
+
+  let sample_rate =
+    match matches.opt_str("sample-rate") {
+      Some(s) => match from_str::<uint>(s.as_slice()) {
+        Some(rate) if rate > 0 => rate,
+        _ => { println!("Invalid sample rate: {:s}", s); std::os::set_exit_status(1); return; },
+      },
+      None => audio::SAMPLE_RATE,
+    };
+  let max_frames =
+    match matches.opt_str("frames") {
+      Some(s) => match from_str::<uint>(s.as_slice()) {
+        Some(frames) => Some(frames),
+        None => { println!("Invalid frame count: {:s}", s); std::os::set_exit_status(1); return; },
+      },
+      None => None,
+    };
+  if matches.opt_present("script") {
+    let conflicts = ["frames", "record", "play"];
+    if !matches.opt_present("headless") || conflicts.iter().any(|o| matches.opt_present(*o)) {
+      println!("--script requires --headless and can't be used with --frames or movies");
+      std::os::set_exit_status(1);
+      return;
+    }
+  }
+  let mut recorder =
+    match matches.opt_str("record-audio") {
+      Some(p) => match wav::AudioRecorder::create(&Path::new(p.as_slice()), sample_rate) {
+        Ok(rec) => Some(rec),
+        Err(e) => { println!("Failed to create {:s}: {}", p, e); std::os::set_exit_status(1); return; },
+      },
+      None => None,
+    };
+
+  let vgm =
+    match matches.opt_str("log-vgm") {
+      Some(p) => match vgm::VgmWriter::create(&Path::new(p.as_slice())) {
+        Ok(vgm) => Some(vgm),
+        Err(e) => { println!("Failed to create {:s}: {}", p, e); std::os::set_exit_status(1); return; },
+      },
+      None => None,
     };
 
-  let mut cart = match cartridge::Cartridge::from_path(&Path::new(path.as_slice())) {
+  let is_gbs = Path::new(path.as_slice()).extension_str()
+                                         .map_or(false, |ext| ext == "gbs" || ext == "GBS");
+  if is_gbs {
+    let track =
+      match matches.opt_str("track") {
+        Some(s) => match from_str::<u8>(s.as_slice()) {
+          Some(track) => Some(track),
+          None => { println!("Invalid track: {:s}", s); std::os::set_exit_status(1); return; },
+        },
+        None => None,
+      };
+    let seconds =
+      match matches.opt_str("length") {
+        Some(s) => match from_str::<uint>(s.as_slice()) {
+          Some(seconds) if seconds > 0 => seconds,
+          _ => { println!("Invalid length: {:s}", s); std::os::set_exit_status(1); return; },
+        },
+        None => DEFAULT_GBS_SECONDS,
+      };
+    let audio_out =
+      if recorder.is_some() || matches.opt_present("no-audio") {
+        None
+      } else {
+        match audio::AudioOut::new() {
+          Ok(audio) => Some(audio),
+          Err(e) => { error!("Failed to open audio device: {}", e); None },
+        }
+      };
+    play_gbs(&Path::new(path.as_slice()), track, seconds, audio_out, recorder, vgm);
+    return;
+  }
+
+  let patch_path = matches.opt_str("patch").map(|p| Path::new(p));
+
+  if matches.opt_present("info") {
+    // Only the header is parsed, so that ROMs the emulator can't run can
+    // still be inspected
+    let rom = match cartridge::read_rom(&Path::new(path.as_slice()), patch_path.as_ref()) {
+      Ok(rom) => rom,
+      Err(e) => {
+        error!("Failed to read {:s}: {}", *path, e);
+        std::os::set_exit_status(1);
+        return;
+      }
+    };
+    if rom.len() < header::HEADER_END {
+      error!("Failed to read header: {}", cartridge::TruncatedFile(rom.len()));
+      std::os::set_exit_status(1);
+      return;
+    }
+    let h = header::Header::parse(rom.as_slice());
+    print_info(&h, rom.len());
+    if !h.logo_valid() || !h.header_checksum_valid() {
+      // The boot ROM would refuse to start this cartridge
+      std::os::set_exit_status(2);
+    }
+    return;
+  }
+
+  let mut cart = match cartridge::Cartridge::from_path(&Path::new(path.as_slice()),
+                                                       patch_path.as_ref()) {
     Ok(cart) => box cart,
-    Err(e)   => panic!("I/O error: {}", e),
+    Err(e)   => {
+      error!("Failed to load {:s}: {}", *path, e);
+      std::os::set_exit_status(1);
+      return;
+    }
   };
 
-  if disassemble {
+  if matches.opt_present("d") {
     // Disassemble only
     let mut d = disasm::Disasm { mem: &mut *cart, pc: 0 };
     while d.pc <= 0x7fff {
@@ -188,23 +1020,186 @@ fn main() {
   println!("Name: {:s}", cart.title);
   println!("Type: {:u}", cart.cartridge_type);
 
-  let memmap = MemMap {
-    cart: cart,
-    wram: ram::WorkRam::new(),
-    timer: timer::Timer::new(),
-    intr: interrupt::InterruptCtrl::new(),
-    sound: sound::Sound,
-    video: video::Video::new(),
-    serial: serial::SerialIO::new(Some(box stdio::stdout() as Box<std::io::Writer>)),
-    joypad: joypad::Joypad::new(),
-    dummy: Dummy,
-  };
-  let mut cpu = cpu::Cpu::new(memmap);
+  // Rumble is shown as an indicator in the window title
+  let rumble = Rc::new(Cell::new(false));
+  if cart.has_rumble() {
+    cart.set_rumble_handler(box RumbleIndicator { active: rumble.clone() });
+  }
+
+  // Movies only record joypad input, so anything else affecting the game
+  // would make them play back differently
+  let movie_mode = matches.opt_present("record") || matches.opt_present("play");
+  let serial_options = ["link-host", "link-connect", "link-local", "printer"];
+  if movie_mode && serial_options.iter().any(|o| matches.opt_present(*o)) {
+    println!("Movies can't be recorded or played with a link cable or printer connected");
+    std::os::set_exit_status(1);
+    return;
+  }
+
+  // The serial port has room for one peer
+  let cable_options = ["link-host", "link-connect"];
+  if matches.opt_present("printer") && cable_options.iter().any(|o| matches.opt_present(*o)) {
+    println!("--printer can't be combined with a link cable");
+    std::os::set_exit_status(1);
+    return;
+  }
+
+  // The in-process second Game Boy takes the first one's serial port
+  let remote_options = ["link-host", "link-connect", "printer"];
+  if matches.opt_present("link-local") && remote_options.iter().any(|o| matches.opt_present(*o)) {
+    println!("--link-local can't be combined with another link cable or printer");
+    std::os::set_exit_status(1);
+    return;
+  }
+
+  let mut cheats = cheats::Cheats::new();
+  let cheats_path = Path::new(path.as_slice()).with_extension("cht");
+  if movie_mode {
+    if cheats_path.exists() {
+      println!("Not loading {}: cheats are disabled with movies", cheats_path.display());
+    }
+    cheats.lock();
+  } else if cheats_path.exists() {
+    match cheats.load(&cheats_path) {
+      Ok(()) => println!("Loaded {:u} cheats from {}", cheats.list().len(), cheats_path.display()),
+      Err(e) => error!("Failed to load cheats: {}", e),
+    }
+  }
+
+  // Input movie. Runs start at power-on with the battery-backed RAM embedded
+  // in the movie, so they don't depend on the save file or wall-clock time.
+  let mut movie =
+    match (matches.opt_str("play"), matches.opt_str("record")) {
+      (Some(play_path), record_path) => {
+        let mut movie = match movie::Movie::load(&Path::new(play_path.as_slice())) {
+          Ok(movie) => movie,
+          Err(e) => {
+            println!("Failed to load movie {:s}: {}", play_path, e);
+            std::os::set_exit_status(1);
+            return;
+          }
+        };
+        if movie.rom_crc != cart.rom_checksum() {
+          println!("Warning: the movie was recorded with a different ROM");
+        }
+        match movie.initial_ram {
+          Some(ref ram) => { cart.restore_save_data(ram.as_slice()); },
+          None => (),
+        }
+        cart.disable_saving();
+        match record_path {
+          Some(p) => movie.set_path(&Path::new(p)),
+          None => (),
+        }
+        println!("Playing movie with {:u} frames", movie.len());
+        Some(movie)
+      },
+      (None, Some(record_path)) => {
+        let initial_ram = if cart.has_battery() { Some(cart.save_data()) } else { None };
+        println!("Recording movie to {:s}", record_path);
+        Some(movie::Movie::new(&Path::new(record_path.as_slice()), cart.rom_checksum(), initial_ram))
+      },
+      (None, None) => None,
+    };
+
+  let mut cpu = cpu::Cpu::new(MemMap::new(cart as Box<cartridge::Slot>, cheats));
   cpu.regs.pc = 0x100;
+  cpu.mem.vgm = vgm;
+
+  let link =
+    match (matches.opt_str("link-host"), matches.opt_str("link-connect")) {
+      (Some(addr), _) => {
+        println!("Waiting for link cable peer on {:s}", addr);
+        Some(link::TcpLink::host(addr.as_slice()))
+      },
+      (None, Some(addr)) => Some(link::TcpLink::connect(addr.as_slice())),
+      (None, None) => None,
+    };
+  match link {
+    Some(Ok(link)) => cpu.mem.serial.set_peer(box link),
+    Some(Err(e)) => {
+      println!("Failed to set up link cable: {}", e);
+      std::os::set_exit_status(1);
+      return;
+    },
+    None => {
+      if matches.opt_present("printer") {
+        // Printouts are saved next to the ROM as <rom>-print-NNN.png
+        cpu.mem.serial.set_peer(box printer::Printer::new(output_base(path.as_slice(), "print")));
+      }
+    },
+  }
 
-  let video_out = VideoOut::new(4);
+  // Second Game Boy connected by an in-memory link cable
+  let mut second =
+    match matches.opt_str("link-local") {
+      Some(second_path) => {
+        // The second Game Boy keeps its own save file, as it usually runs
+        // the same ROM as the first
+        let second_rom = Path::new(second_path.as_slice());
+        let second_save = output_base(second_path.as_slice(), "2").with_extension("sav");
+        let second_cart = match cartridge::Cartridge::with_save_path(&second_rom, None, &second_save) {
+          Ok(cart) => box cart,
+          Err(e)   => {
+            error!("Failed to load {:s}: {}", second_path, e);
+            std::os::set_exit_status(1);
+            return;
+          }
+        };
+        println!("Linked to: {:s}", second_cart.title);
+
+        let mut second_cpu = cpu::Cpu::new(MemMap::new(second_cart as Box<cartridge::Slot>,
+                                                       cheats::Cheats::new()));
+        second_cpu.regs.pc = 0x100;
+        let (first_end, second_end) = link::local_pair();
+        cpu.mem.serial.set_peer(box first_end);
+        second_cpu.mem.serial.set_peer(box second_end);
+        Some(second_cpu)
+      },
+      None => None,
+    };
+
+  if matches.opt_present("headless") {
+    let mut headless = Headless::new(cpu, second);
+    if matches.opt_present("script") {
+      run_script(&mut headless, &output_base(path.as_slice(), "screenshot"), &mut recorder);
+    } else {
+      run_headless(&mut headless, &mut movie, max_frames, &mut recorder);
+    }
+    finish_recording(&mut recorder);
+    headless.shutdown();
+    save_movie(&movie);
+    return;
+  }
+
+  let video_out = VideoOut::new(4, if second.is_some() { 2 } else { 1 });
   video_out.set_title("Rustboy");
 
+  let mut audio_out =
+    if matches.opt_present("no-audio") {
+      None
+    } else {
+      match audio::AudioOut::new() {
+        Ok(audio) => Some(audio),
+        Err(e) => { error!("Failed to open audio device: {}", e); None },
+      }
+    };
+  let audio_sync = matches.opt_present("audio-sync") && audio_out.is_some();
+
+  let mut scope_out: Option<ScopeOut> = None;
+
+  let mut input = input::Input::new();
+  let config_path = Path::new(matches.opt_str("config").unwrap_or("rustboy.toml".to_string()));
+  if matches.opt_present("config") || config_path.exists() {
+    match input.load(&config_path) {
+      Ok(()) => println!("Loaded bindings from {}", config_path.display()),
+      Err(e) => error!("Failed to load bindings: {}", e),
+    }
+  }
+  let screenshot_base = output_base(path.as_slice(), "screenshot");
+  let mut fast_forward = false;
+  let mut controls = turbo::Controls::new(input.turbo_rate());
+
   let mut state = Paused;
   let mut debugger = debug::Debugger::new();
 
@@ -214,6 +1209,10 @@ fn main() {
 
   let mut last_fps_update = last_frame_start_count;
   let mut frames = 0;
+  let mut fps = 0;
+  let mut rumble_shown = false;
+  let mut control_second = false; // Keyboard drives the linked Game Boy
+  let mut vblank = false; // Movie input is applied after this frame's events
 
   println!("c/s: {:u}; c/f: {:u}", counts_per_sec, counts_per_frame);
 
@@ -228,51 +1227,73 @@ fn main() {
 
     // Emulation loop
     loop {
-      let cycles = cpu.step();
-
-      match cpu.mem.timer.tick(cycles) {
-        Some(timer::TIMAOverflow) => cpu.mem.intr.irq(interrupt::IRQ_TIMER),
+      let (cycles, new_frame) = step(&mut cpu);
+      match second {
+        Some(ref mut second) => { catch_up(cpu.cycles, second); },
         None => (),
       }
 
-      let mut new_frame = false;
-      let video_signals = cpu.mem.video.tick(cycles);
-      for signal in video_signals.iter() {
-        match *signal {
-          video::DMA(base) => {
-            // Do DMA transfer instantaneously
-            let base_addr = base as u16 << 8;
-            for offset in range(0x00u16, 0xa0u16) {
-              let val = cpu.mem.loadb(base_addr + offset);
-              cpu.mem.storeb(0xfe00 + offset, val);
-            }
-          },
-          video::VBlank => {
-            video_out.blit_and_present(cpu.mem.video.screen);
-            cpu.mem.intr.irq(interrupt::IRQ_VBLANK);
-            new_frame = true;
-          }
-          video::LCD    => cpu.mem.intr.irq(interrupt::IRQ_LCD),
-        }
+      let output = cpu.mem.sound.output();
+      match audio_out {
+        Some(ref mut audio) => audio.push(output, cycles),
+        None => (),
+      }
+      match recorder {
+        Some(ref mut rec) => rec.push(output, cycles),
+        None => (),
       }
 
-      // Synchronize speed based on frame time
       if new_frame {
+        match second {
+          Some(ref second) => video_out.blit_and_present(&[cpu.mem.video.screen.as_slice(),
+                                                           second.mem.video.screen.as_slice()]),
+          None => video_out.blit_and_present(&[cpu.mem.video.screen.as_slice()]),
+        }
+        match scope_out {
+          Some(ref scope) => scope.draw(&cpu.mem.sound),
+          None => (),
+        }
+
+        // Turbo buttons and macros advance with emulated frames
+        for &(button, pressed) in controls.frame().iter() {
+          press_button(&mut cpu, &mut second, &mut movie, control_second, button, pressed);
+        }
+        vblank = true;
+
+        match audio_out {
+          Some(ref mut audio) => audio.flush(),
+          None => (),
+        }
+        flush_recording(&mut recorder);
+
         let now = sdl2::timer::get_performance_counter();
-        let frame_time = now - last_frame_start_count;
-        if frame_time < counts_per_frame {
-          let delay_msec = (1_000 * (counts_per_frame - frame_time) / counts_per_sec) as uint;
-          sdl2::timer::delay(delay_msec);
+        match audio_out {
+          // Run as fast as possible
+          _ if fast_forward => (),
+          // Synchronize speed based on audio buffer level
+          Some(ref audio) if audio_sync => audio.wait(),
+          // Synchronize speed based on frame time. A frame that took too long
+          // is not caught up on, the next one is simply timed from now.
+          _ => {
+            let frame_time = now - last_frame_start_count;
+            if frame_time < counts_per_frame {
+              let delay_msec = (1_000 * (counts_per_frame - frame_time) / counts_per_sec) as uint;
+              sdl2::timer::delay(delay_msec);
+            }
+          },
         }
-        // TODO: What should we do when we take longer than counts_per_frame?
         last_frame_start_count = sdl2::timer::get_performance_counter();
 
         frames += 1;
         if last_frame_start_count - last_fps_update > counts_per_sec {
-          let fps = frames * (last_frame_start_count - last_fps_update) / counts_per_sec;
-          video_out.set_title(format!("Rustboy - {} fps", fps).as_slice());
+          fps = frames * (last_frame_start_count - last_fps_update) / counts_per_sec;
+          video_out.set_title(window_title(fps, rumble.get()).as_slice());
+          rumble_shown = rumble.get();
           last_fps_update = now;
           frames = 0;
+        } else if rumble.get() != rumble_shown {
+          video_out.set_title(window_title(fps, rumble.get()).as_slice());
+          rumble_shown = rumble.get();
         }
 
         // Exit emulation loop to handle events
@@ -292,28 +1313,86 @@ fn main() {
 
     // Event handling loop
     loop {
-      match sdl2::event::poll_event() {
+      let event = sdl2::event::poll_event();
+      match event {
         sdl2::event::QuitEvent(_) => { state = Done; break }
-        sdl2::event::KeyDownEvent(_, _, key, _, _) => {
-          match keymap(key) {
-            Some(button) => cpu.mem.joypad.set_button(button, true),
-            None => {
-              match key {
-                sdl2::keycode::EscapeKey => { state = Paused },
-                _ => (),
-              }
-            }
-          }
-        },
-        sdl2::event::KeyUpEvent(_, _, key, _, _) => {
-          match keymap(key) {
-            Some(button) => cpu.mem.joypad.set_button(button, false),
-            None => (),
-          }
-        }
         sdl2::event::NoEvent => break,
         _ => (),
       }
+
+      for &(action, pressed) in input.translate(&event).iter() {
+        match action {
+          input::Joypad(button) => {
+            let pressed = controls.set_held(button, pressed);
+            press_button(&mut cpu, &mut second, &mut movie, control_second, button, pressed);
+          },
+          input::Turbo(button) => {
+            let pressed = controls.set_turbo(button, pressed);
+            press_button(&mut cpu, &mut second, &mut movie, control_second, button, pressed);
+          },
+          input::Frontend(input::Pause) => if pressed { state = Paused },
+          input::Frontend(input::FastForward) => fast_forward = pressed,
+          input::Frontend(input::Screenshot) => if pressed {
+            save_screenshot(cpu.mem.video.screen.as_slice(), &screenshot_base);
+          },
+          input::Frontend(input::RunMacro(index)) => if pressed {
+            controls.start_macro(input.macro_steps(index));
+          },
+          input::Frontend(input::SwitchPlayer) => if pressed && second.is_some() {
+            // Held, turbo and macro buttons move over to the other joypad
+            let buttons = controls.pressed();
+            for &button in buttons.iter() {
+              press_button(&mut cpu, &mut second, &mut movie, control_second, button, false);
+            }
+            control_second = !control_second;
+            for &button in buttons.iter() {
+              press_button(&mut cpu, &mut second, &mut movie, control_second, button, true);
+            }
+          },
+          // Sound channel mute and solo
+          input::Frontend(input::ToggleMute(channel)) => if pressed {
+            cpu.mem.sound.toggle_mute(channel);
+          },
+          input::Frontend(input::ToggleSolo(channel)) => if pressed {
+            cpu.mem.sound.toggle_solo(channel);
+          },
+          // Channel oscilloscope window
+          input::Frontend(input::Oscilloscope) => if pressed {
+            let enable = scope_out.is_none();
+            cpu.mem.sound.set_tap_enabled(enable);
+            scope_out = if enable { Some(ScopeOut::new()) } else { None };
+          },
+          input::Frontend(input::MovieReadOnly) => if pressed {
+            match movie {
+              Some(ref movie) if movie.is_read_only() && movie.path().is_none() => {
+                println!("Pass --record FILE along with --play to branch off the movie");
+              },
+              Some(ref mut movie) => {
+                let mode = if movie.toggle_read_only() { "read-only" } else { "read-write" };
+                println!("Movie {:s} at frame {:u}", mode, movie.position());
+              },
+              None => (),
+            }
+          },
+        }
+      }
+    }
+
+    // The movie decides the first Game Boy's buttons for the next frame
+    if vblank {
+      match movie {
+        Some(ref mut movie) => cpu.mem.joypad.set_buttons(movie.frame()),
+        None => (),
+      }
+      vblank = false;
     }
   }
+
+  finish_recording(&mut recorder);
+  shutdown(&mut cpu);
+  save_movie(&movie);
+  match second {
+    Some(ref mut second) => shutdown(second),
+    None => (),
+  }
 }
@@ -0,0 +1,178 @@
+use joypad;
+use rtc;
+use std::io::{File, IoError, IoResult, OtherIoError};
+
+//
+// Input movies
+//
+// A movie holds the buttons pressed during each frame, sampled at V-Blank,
+// along with the ROM checksum and the initial battery-backed RAM. Together
+// with power-on state this is enough to replay a run exactly. While the LCD
+// is off or the CPU is stopped, there is no V-Blank and movie frames advance
+// on the frames reported every SCREEN_REFRESH_CYCLES instead.
+//
+// File format (little-endian):
+//   "RBM\x1a", version (u16), flags (u16), ROM CRC-32 (u32),
+//   frame count (u32), initial RAM size (u32), initial RAM,
+//   one byte per frame with bit n set if joypad::Button n is pressed,
+//   up to the end of the file
+//
+
+const MAGIC: &'static [u8] = b"RBM\x1a";
+const VERSION: u16 = 1;
+const FLAG_INITIAL_RAM: u16 = 0x0001;
+
+// Largest cartridge RAM (128 KiB) followed by the RTC footer
+const MAX_INITIAL_RAM: uint = 0x20000 + rtc::FOOTER_SIZE;
+
+pub struct Movie {
+  pub rom_crc: u32,
+  pub initial_ram: Option<Vec<u8>>, // Cartridge RAM and RTC, as in a .sav file
+  frames: Vec<u8>,
+  position: uint, // Next frame to play or record
+  read_only: bool,
+  input: u8,      // Live input to be recorded
+  modified: bool, // Frames have been recorded since loading
+  path: Option<Path>, // Recordings and branches are saved here
+}
+
+fn format_error(desc: &'static str) -> IoError {
+  IoError { kind: OtherIoError, desc: desc, detail: None }
+}
+
+impl Movie {
+  // Starts recording a new movie
+  pub fn new(path: &Path, rom_crc: u32, initial_ram: Option<Vec<u8>>) -> Movie {
+    Movie {
+      rom_crc: rom_crc,
+      initial_ram: initial_ram,
+      frames: vec!(),
+      position: 0,
+      read_only: false,
+      input: 0,
+      modified: false,
+      path: Some(path.clone()),
+    }
+  }
+
+  // Loads a movie for read-only playback. It is never saved back to the same
+  // file, branches need a path to be set first.
+  pub fn load(path: &Path) -> IoResult<Movie> {
+    let mut file = try!(File::open(path));
+    if try!(file.read_exact(MAGIC.len())).as_slice() != MAGIC {
+      return Err(format_error("not a rustboy movie"));
+    }
+    if try!(file.read_le_u16()) != VERSION {
+      return Err(format_error("unsupported movie version"));
+    }
+    let flags = try!(file.read_le_u16());
+    let rom_crc = try!(file.read_le_u32());
+    let frame_count = try!(file.read_le_u32()) as uint;
+    let ram_size = try!(file.read_le_u32()) as uint;
+    if ram_size > MAX_INITIAL_RAM {
+      return Err(format_error("invalid initial RAM size"));
+    }
+    let ram = try!(file.read_exact(ram_size));
+    let frames = try!(file.read_to_end());
+    if frames.len() != frame_count {
+      return Err(format_error("frame count doesn't match the file size"));
+    }
+
+    Ok(Movie {
+      rom_crc: rom_crc,
+      initial_ram: if (flags & FLAG_INITIAL_RAM) != 0 { Some(ram) } else { None },
+      frames: frames,
+      position: 0,
+      read_only: true,
+      input: 0,
+      modified: false,
+      path: None,
+    })
+  }
+
+  pub fn save(&self) -> IoResult<()> {
+    let path = match self.path {
+      Some(ref path) => path,
+      None => return Err(format_error("no path to save the movie to")),
+    };
+    let mut file = try!(File::create(path));
+    try!(file.write(MAGIC));
+    try!(file.write_le_u16(VERSION));
+    try!(file.write_le_u16(if self.initial_ram.is_some() { FLAG_INITIAL_RAM } else { 0 }));
+    try!(file.write_le_u32(self.rom_crc));
+    try!(file.write_le_u32(self.frames.len() as u32));
+    match self.initial_ram {
+      Some(ref ram) => {
+        try!(file.write_le_u32(ram.len() as u32));
+        try!(file.write(ram.as_slice()));
+      },
+      None => try!(file.write_le_u32(0)),
+    }
+    file.write(self.frames.as_slice())
+  }
+
+  // Sets where the movie is saved, e.g. to branch off a loaded movie
+  pub fn set_path(&mut self, path: &Path) {
+    self.path = Some(path.clone());
+  }
+
+  pub fn path(&self) -> Option<&Path> {
+    self.path.as_ref()
+  }
+
+  pub fn position(&self) -> uint {
+    self.position
+  }
+
+  pub fn len(&self) -> uint {
+    self.frames.len()
+  }
+
+  // Whether there is anything new to save
+  pub fn is_modified(&self) -> bool {
+    self.modified
+  }
+
+  pub fn is_read_only(&self) -> bool {
+    self.read_only
+  }
+
+  // Switching to read-write branches off: recording continues from the
+  // current frame and replaces the rest of the movie. This needs a path to
+  // save to, otherwise the movie stays read-only.
+  pub fn toggle_read_only(&mut self) -> bool {
+    if self.read_only && self.path.is_none() {
+      return true;
+    }
+    self.read_only = !self.read_only;
+    self.read_only
+  }
+
+  // Live input, recorded at the next frame in read-write mode
+  pub fn set_button(&mut self, button: joypad::Button, pressed: bool) {
+    let bit = 1 << button as uint;
+    if pressed {
+      self.input |= bit;
+    } else {
+      self.input &= !bit;
+    }
+  }
+
+  // Called once per frame at V-Blank. Returns the buttons to hold during the
+  // next frame. Once a read-only movie has ended, live input takes over.
+  pub fn frame(&mut self) -> u8 {
+    if self.read_only {
+      if self.position < self.frames.len() {
+        self.position += 1;
+        return self.frames[self.position - 1];
+      }
+      return self.input;
+    }
+
+    self.frames.truncate(self.position);
+    self.frames.push(self.input);
+    self.modified = true;
+    self.position += 1;
+    self.input
+  }
+}
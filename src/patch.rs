@@ -0,0 +1,285 @@
+use crc32;
+use std::fmt;
+
+//
+// Soft-patching (IPS, UPS, BPS)
+//
+
+const IPS_MAGIC: &'static [u8] = b"PATCH";
+const IPS_EOF: uint = 0x454f46; // "EOF"
+const UPS_MAGIC: &'static [u8] = b"UPS1";
+const BPS_MAGIC: &'static [u8] = b"BPS1";
+
+const FOOTER_SIZE: uint = 12; // Source, target and patch CRC32 (UPS/BPS)
+
+pub enum PatchError {
+  UnknownFormat,
+  TruncatedPatch,
+  SourceSizeMismatch(uint, uint), // Expected, actual
+  SourceChecksumMismatch,
+  TargetChecksumMismatch,
+  PatchChecksumMismatch,
+  InvalidOffset(uint),
+}
+
+impl fmt::Show for PatchError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      UnknownFormat                    => write!(f, "unknown patch format"),
+      TruncatedPatch                   => write!(f, "patch is truncated"),
+      SourceSizeMismatch(exp, act)     => write!(f, "patch expects a {:u} byte ROM, got {:u} bytes", exp, act),
+      SourceChecksumMismatch           => write!(f, "patch does not match this ROM (source CRC32 mismatch)"),
+      TargetChecksumMismatch           => write!(f, "patched ROM is corrupt (target CRC32 mismatch)"),
+      PatchChecksumMismatch            => write!(f, "patch file is corrupt (patch CRC32 mismatch)"),
+      InvalidOffset(offset)            => write!(f, "patch refers to invalid offset 0x{:X}", offset),
+    }
+  }
+}
+
+pub type PatchResult<T> = Result<T, PatchError>;
+
+// Applies an IPS, UPS or BPS patch, detected by its magic bytes, to a ROM.
+pub fn apply(rom: &[u8], patch: &[u8]) -> PatchResult<Vec<u8>> {
+  if patch.starts_with(IPS_MAGIC) {
+    apply_ips(rom, patch)
+  } else if patch.starts_with(UPS_MAGIC) {
+    apply_ups(rom, patch)
+  } else if patch.starts_with(BPS_MAGIC) {
+    apply_bps(rom, patch)
+  } else {
+    Err(UnknownFormat)
+  }
+}
+
+struct PatchReader<'a> {
+  data: &'a [u8],
+  pos: uint,
+}
+
+impl<'a> PatchReader<'a> {
+  fn new(data: &'a [u8], pos: uint) -> PatchReader<'a> {
+    PatchReader { data: data, pos: pos }
+  }
+
+  fn byte(&mut self) -> PatchResult<u8> {
+    if self.pos >= self.data.len() {
+      return Err(TruncatedPatch);
+    }
+    let b = self.data[self.pos];
+    self.pos += 1;
+    Ok(b)
+  }
+
+  fn bytes(&mut self, len: uint) -> PatchResult<&'a [u8]> {
+    if self.pos + len > self.data.len() {
+      return Err(TruncatedPatch);
+    }
+    let result = self.data.slice(self.pos, self.pos + len);
+    self.pos += len;
+    Ok(result)
+  }
+
+  // Big-endian integer of the given width (IPS)
+  fn be(&mut self, len: uint) -> PatchResult<uint> {
+    let mut val = 0u;
+    for _ in range(0, len) {
+      val = (val << 8) | try!(self.byte()) as uint;
+    }
+    Ok(val)
+  }
+
+  // Variable-length integer (UPS/BPS)
+  fn varint(&mut self) -> PatchResult<uint> {
+    let mut val = 0u;
+    let mut shift = 1u;
+    loop {
+      let x = try!(self.byte());
+      val += (x & 0x7f) as uint * shift;
+      if (x & 0x80) != 0 {
+        return Ok(val);
+      }
+      shift <<= 7;
+      val += shift;
+    }
+  }
+}
+
+fn read_le32(data: &[u8]) -> u32 {
+  data.iter().rev().fold(0u32, |acc, &b| (acc << 8) | b as u32)
+}
+
+fn apply_ips(rom: &[u8], patch: &[u8]) -> PatchResult<Vec<u8>> {
+  let mut out = rom.to_vec();
+  let mut r = PatchReader::new(patch, IPS_MAGIC.len());
+
+  loop {
+    let offset = try!(r.be(3));
+    if offset == IPS_EOF {
+      break;
+    }
+
+    let size = try!(r.be(2));
+    let (len, run) =
+      if size == 0 {
+        // RLE record: 16-bit run length followed by the fill byte
+        let len = try!(r.be(2));
+        (len, Some(try!(r.byte())))
+      } else {
+        (size, None)
+      };
+
+    if offset + len > out.len() {
+      out.grow(offset + len - out.len(), 0u8);
+    }
+    match run {
+      Some(val) => {
+        for i in range(offset, offset + len) {
+          *out.get_mut(i) = val;
+        }
+      },
+      None => {
+        let data = try!(r.bytes(len));
+        for (i, &b) in data.iter().enumerate() {
+          *out.get_mut(offset + i) = b;
+        }
+      }
+    }
+  }
+
+  // Optional truncation extension
+  if r.pos + 3 <= patch.len() {
+    let size = try!(r.be(3));
+    out.truncate(size);
+  }
+
+  Ok(out)
+}
+
+// Checks the UPS/BPS footer and returns the expected source and target CRC32.
+fn check_footer(patch: &[u8]) -> PatchResult<(u32, u32)> {
+  if patch.len() < 4 + FOOTER_SIZE {
+    return Err(TruncatedPatch);
+  }
+  let footer = patch.slice_from(patch.len() - FOOTER_SIZE);
+  if crc32::checksum(patch.slice_to(patch.len() - 4)) != read_le32(footer.slice_from(8)) {
+    return Err(PatchChecksumMismatch);
+  }
+  Ok((read_le32(footer.slice(0, 4)), read_le32(footer.slice(4, 8))))
+}
+
+fn apply_ups(rom: &[u8], patch: &[u8]) -> PatchResult<Vec<u8>> {
+  let (source_crc, target_crc) = try!(check_footer(patch));
+  let end = patch.len() - FOOTER_SIZE;
+  let mut r = PatchReader::new(patch.slice_to(end), UPS_MAGIC.len());
+
+  let source_size = try!(r.varint());
+  let target_size = try!(r.varint());
+  if source_size != rom.len() {
+    return Err(SourceSizeMismatch(source_size, rom.len()));
+  }
+  if crc32::checksum(rom) != source_crc {
+    return Err(SourceChecksumMismatch);
+  }
+
+  let mut out = rom.to_vec();
+  if target_size > out.len() {
+    out.grow(target_size - rom.len(), 0u8);
+  } else {
+    out.truncate(target_size);
+  }
+
+  // Runs of XOR bytes, each terminated by 0x00, separated by relative skips
+  let mut pos = 0u;
+  while r.pos < end {
+    pos += try!(r.varint());
+    loop {
+      let x = try!(r.byte());
+      if x == 0 {
+        pos += 1;
+        break;
+      }
+      if pos >= target_size {
+        return Err(InvalidOffset(pos));
+      }
+      *out.get_mut(pos) ^= x;
+      pos += 1;
+    }
+  }
+
+  if crc32::checksum(out.as_slice()) != target_crc {
+    return Err(TargetChecksumMismatch);
+  }
+  Ok(out)
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> PatchResult<Vec<u8>> {
+  let (source_crc, target_crc) = try!(check_footer(patch));
+  let end = patch.len() - FOOTER_SIZE;
+  let mut r = PatchReader::new(patch.slice_to(end), BPS_MAGIC.len());
+
+  let source_size = try!(r.varint());
+  let target_size = try!(r.varint());
+  let metadata_size = try!(r.varint());
+  try!(r.bytes(metadata_size));
+  if source_size != rom.len() {
+    return Err(SourceSizeMismatch(source_size, rom.len()));
+  }
+  if crc32::checksum(rom) != source_crc {
+    return Err(SourceChecksumMismatch);
+  }
+
+  let mut out: Vec<u8> = Vec::with_capacity(target_size);
+  let mut source_offset = 0i;
+  let mut target_offset = 0i;
+
+  while r.pos < end {
+    let data = try!(r.varint());
+    let len = (data >> 2) + 1;
+    if out.len() + len > target_size {
+      return Err(InvalidOffset(out.len() + len));
+    }
+
+    match data & 0b11 {
+      0 => { // SourceRead
+        let start = out.len();
+        if start + len > rom.len() {
+          return Err(InvalidOffset(start + len));
+        }
+        out.push_all(rom.slice(start, start + len));
+      },
+      1 => { // TargetRead
+        out.push_all(try!(r.bytes(len)));
+      },
+      2 => { // SourceCopy
+        source_offset += signed_offset(try!(r.varint()));
+        if source_offset < 0 || source_offset as uint + len > rom.len() {
+          return Err(InvalidOffset(source_offset as uint));
+        }
+        let start = source_offset as uint;
+        out.push_all(rom.slice(start, start + len));
+        source_offset += len as int;
+      },
+      _ => { // TargetCopy (may overlap the output being written)
+        target_offset += signed_offset(try!(r.varint()));
+        if target_offset < 0 || target_offset as uint >= out.len() {
+          return Err(InvalidOffset(target_offset as uint));
+        }
+        for _ in range(0, len) {
+          let b = out[target_offset as uint];
+          out.push(b);
+          target_offset += 1;
+        }
+      },
+    }
+  }
+
+  if out.len() != target_size || crc32::checksum(out.as_slice()) != target_crc {
+    return Err(TargetChecksumMismatch);
+  }
+  Ok(out)
+}
+
+fn signed_offset(data: uint) -> int {
+  let magnitude = (data >> 1) as int;
+  if (data & 1) != 0 { -magnitude } else { magnitude }
+}
@@ -0,0 +1,112 @@
+use crc32;
+use std::cmp;
+use std::io::{File, IoResult, MemWriter};
+
+//
+// PNG Output
+//
+
+const SIGNATURE: &'static [u8] = &[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+const COLOR_GRAYSCALE: u8 = 0;
+const COLOR_RGB: u8 = 2;
+const FILTER_NONE: u8 = 0;
+
+const MAX_STORED_BLOCK: uint = 0xffff;
+const ADLER_MODULO: u32 = 65521;
+
+fn adler32(data: &[u8]) -> u32 {
+  let mut a = 1u32;
+  let mut b = 0u32;
+  for &byte in data.iter() {
+    a = (a + byte as u32) % ADLER_MODULO;
+    b = (b + a) % ADLER_MODULO;
+  }
+  (b << 16) | a
+}
+
+// Wraps data in a zlib stream of uncompressed deflate blocks. Printed images
+// are tiny, so compressing them isn't worth an encoder.
+fn zlib_stored(data: &[u8]) -> IoResult<Vec<u8>> {
+  let mut out = MemWriter::new();
+  try!(out.write(&[0x78, 0x01]));
+
+  let mut offset = 0;
+  loop {
+    let len = cmp::min(data.len() - offset, MAX_STORED_BLOCK);
+    let last = offset + len == data.len();
+    try!(out.write_u8(if last { 1 } else { 0 }));
+    try!(out.write_le_u16(len as u16));
+    try!(out.write_le_u16(!(len as u16)));
+    try!(out.write(data.slice(offset, offset + len)));
+    offset += len;
+    if last {
+      break;
+    }
+  }
+
+  try!(out.write_be_u32(adler32(data)));
+  Ok(out.unwrap())
+}
+
+fn write_chunk(file: &mut File, kind: &[u8], data: &[u8]) -> IoResult<()> {
+  let mut crc = crc32::Crc32::new();
+  crc.update(kind);
+  crc.update(data);
+
+  try!(file.write_be_u32(data.len() as u32));
+  try!(file.write(kind));
+  try!(file.write(data));
+  file.write_be_u32(crc.value())
+}
+
+fn write_image(path: &Path,
+               width: uint,
+               height: uint,
+               color: u8,
+               bytes_per_pixel: uint,
+               pixels: &[u8]) -> IoResult<()> {
+  let mut file = try!(File::create(path));
+  try!(file.write(SIGNATURE));
+
+  let mut header = MemWriter::new();
+  try!(header.write_be_u32(width as u32));
+  try!(header.write_be_u32(height as u32));
+  try!(header.write(&[8, color, 0, 0, 0])); // depth, color, compression, filter, interlace
+  try!(write_chunk(&mut file, b"IHDR", header.get_ref()));
+
+  // Every scanline starts with its filter type
+  let row_bytes = width * bytes_per_pixel;
+  let mut raw = Vec::with_capacity((row_bytes + 1) * height);
+  for row in pixels.chunks(row_bytes) {
+    raw.push(FILTER_NONE);
+    raw.push_all(row);
+  }
+  let compressed = try!(zlib_stored(raw.as_slice()));
+  try!(write_chunk(&mut file, b"IDAT", compressed.as_slice()));
+
+  write_chunk(&mut file, b"IEND", &[])
+}
+
+// Writes an 8-bit grayscale image, one byte per pixel
+pub fn write_grayscale(path: &Path, width: uint, height: uint, pixels: &[u8]) -> IoResult<()> {
+  write_image(path, width, height, COLOR_GRAYSCALE, 1, pixels)
+}
+
+// Writes an 8-bit RGB image, three bytes per pixel
+pub fn write_rgb(path: &Path, width: uint, height: uint, pixels: &[u8]) -> IoResult<()> {
+  write_image(path, width, height, COLOR_RGB, 3, pixels)
+}
+
+// Returns the first <base>-NNN.png path that doesn't exist yet
+pub fn numbered_path(base: &Path) -> Path {
+  let stem = base.filename_str().unwrap_or("image");
+  let mut number = 1u;
+  loop {
+    let path = base.with_filename(format!("{:s}-{:03u}.png", stem, number));
+    if !path.exists() {
+      return path;
+    }
+    number += 1;
+  }
+}
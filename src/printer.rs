@@ -0,0 +1,276 @@
+use png;
+use serial;
+use std::cmp;
+use std::io::IoResult;
+
+//
+// Statics
+//
+
+const MAGIC: [u8, ..2] = [0x88, 0x33];
+const DEVICE_ID: u8 = 0x81;
+
+// Commands
+const CMD_INIT: u8 = 0x01;
+const CMD_PRINT: u8 = 0x02;
+const CMD_DATA: u8 = 0x04;
+const CMD_STATUS: u8 = 0x0f;
+
+// Status flags
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_IMAGE_DATA_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED_DATA: u8 = 0x08;
+
+const HEADER_SIZE: uint = 6; // magic, command, compression, length
+const MAX_DATA_SIZE: uint = 0x280; // One band: 2 rows of 20 tiles
+
+const WIDTH_TILES: uint = 20;
+const TILE_BYTES: uint = 16;
+const MAX_BUFFER_SIZE: uint = 9 * MAX_DATA_SIZE;
+
+// Status inquiries answered with "printing" after a print command
+const PRINT_POLLS: uint = 4;
+
+// Gray level of each shade, white to black
+const SHADES: [u8, ..4] = [0xff, 0xaa, 0x55, 0x00];
+
+
+//
+// Game Boy Printer
+//
+
+// Packet reception state, with the index of the next byte within each part
+enum Receive {
+  Magic(uint),
+  Header(uint),
+  Data(uint),
+  Checksum(uint),
+  Acknowledge, // Printer answers with its device ID
+  Status,      // Printer answers with its status
+}
+
+pub struct Printer {
+  state: Receive,
+  header: [u8, ..HEADER_SIZE],
+  data: Vec<u8>,
+  checksum: u16,
+  reply: Option<u8>,
+
+  status: u8,
+  buffer: Vec<u8>, // Received tile data
+  busy_polls: uint,
+
+  path_base: Path, // Printouts are saved as <path_base>-NNN.png
+}
+
+// Expands run-length encoded packet data
+fn decompress(data: &[u8]) -> Vec<u8> {
+  let mut out = Vec::new();
+  let mut i = 0;
+  while i < data.len() {
+    let control = data[i];
+    i += 1;
+    if (control & 0x80) != 0 {
+      // Run of one repeated byte
+      let count = (control & 0x7f) as uint + 2;
+      if i < data.len() {
+        out.grow(count, data[i]);
+      }
+      i += 1;
+    } else {
+      // Literal bytes
+      let count = cmp::min(control as uint + 1, data.len() - i);
+      out.push_all(data.slice(i, i + count));
+      i += count;
+    }
+  }
+  out
+}
+
+impl Printer {
+  pub fn new(path_base: Path) -> Printer {
+    Printer {
+      state: Magic(0),
+      header: [0u8, ..HEADER_SIZE],
+      data: Vec::new(),
+      checksum: 0,
+      reply: None,
+      status: 0,
+      buffer: Vec::new(),
+      busy_polls: 0,
+      path_base: path_base,
+    }
+  }
+
+  fn command(&self) -> u8 {
+    self.header[2]
+  }
+
+  fn data_length(&self) -> uint {
+    self.header[4] as uint | (self.header[5] as uint << 8)
+  }
+
+  // Sum of all bytes from the command to the end of the data
+  fn expected_checksum(&self) -> u16 {
+    let mut sum = 0u16;
+    for &b in self.header.slice_from(2).iter().chain(self.data.iter()) {
+      sum += b as u16;
+    }
+    sum
+  }
+
+  // Handles one byte sent by the Game Boy and returns the printer's answer
+  fn receive(&mut self, byte: u8) -> u8 {
+    let (next, reply) =
+      match self.state {
+        Magic(i) => {
+          if byte != MAGIC[i] {
+            (Magic(if byte == MAGIC[0] { 1 } else { 0 }), 0x00)
+          } else if i + 1 < MAGIC.len() {
+            (Magic(i + 1), 0x00)
+          } else {
+            self.header[0] = MAGIC[0];
+            self.header[1] = MAGIC[1];
+            (Header(2), 0x00)
+          }
+        },
+        Header(i) => {
+          self.header[i] = byte;
+          if i + 1 < HEADER_SIZE {
+            (Header(i + 1), 0x00)
+          } else {
+            self.data.clear();
+            self.checksum = 0;
+            if self.data_length() > 0 { (Data(0), 0x00) } else { (Checksum(0), 0x00) }
+          }
+        },
+        Data(i) => {
+          self.data.push(byte);
+          if i + 1 < self.data_length() { (Data(i + 1), 0x00) } else { (Checksum(0), 0x00) }
+        },
+        Checksum(0) => {
+          self.checksum = byte as u16;
+          (Checksum(1), 0x00)
+        },
+        Checksum(_) => {
+          self.checksum |= byte as u16 << 8;
+          (Acknowledge, 0x00)
+        },
+        Acknowledge => (Status, DEVICE_ID),
+        Status => {
+          self.execute();
+          (Magic(0), self.status)
+        },
+      };
+    self.state = next;
+    reply
+  }
+
+  fn execute(&mut self) {
+    if self.checksum != self.expected_checksum() {
+      self.status |= STATUS_CHECKSUM_ERROR;
+      return;
+    }
+    self.status &= !STATUS_CHECKSUM_ERROR;
+
+    match self.command() {
+      CMD_INIT => {
+        self.buffer.clear();
+        self.status = 0;
+        self.busy_polls = 0;
+      },
+      CMD_DATA => {
+        let data =
+          if (self.header[3] & 0x01) != 0 {
+            decompress(self.data.as_slice())
+          } else {
+            self.data.clone()
+          };
+        // An empty data packet just marks the end of the image
+        if self.buffer.len() + data.len() <= MAX_BUFFER_SIZE {
+          self.buffer.push_all(data.as_slice());
+        }
+        if self.buffer.len() > 0 {
+          self.status |= STATUS_UNPROCESSED_DATA;
+        }
+        if self.buffer.len() == MAX_BUFFER_SIZE {
+          self.status |= STATUS_IMAGE_DATA_FULL;
+        }
+      },
+      CMD_PRINT => {
+        if self.data.len() >= 4 {
+          // A palette of 0 is treated like the usual 0xe4 by the printer
+          let palette = if self.data[2] == 0 { 0xe4 } else { self.data[2] };
+          match self.print(palette) {
+            Err(e) => error!("Failed to save printout: {}", e),
+            _ => (),
+          }
+        }
+        self.buffer.clear();
+        self.status = STATUS_PRINTING | STATUS_IMAGE_DATA_FULL;
+        self.busy_polls = PRINT_POLLS;
+      },
+      CMD_STATUS => {
+        if self.busy_polls > 0 {
+          self.busy_polls -= 1;
+          if self.busy_polls == 0 {
+            self.status &= !(STATUS_PRINTING | STATUS_IMAGE_DATA_FULL | STATUS_UNPROCESSED_DATA);
+          }
+        }
+      },
+      cmd => debug!("unknown printer command 0x{:02X}", cmd),
+    }
+  }
+
+  // Renders the buffered tile data with the given palette (like BGP) and
+  // saves it as the next numbered PNG file
+  fn print(&self, palette: u8) -> IoResult<()> {
+    let tile_rows = self.buffer.len() / (WIDTH_TILES * TILE_BYTES);
+    if tile_rows == 0 {
+      return Ok(());
+    }
+
+    let width = WIDTH_TILES * 8;
+    let height = tile_rows * 8;
+    let mut pixels = Vec::from_elem(width * height, 0u8);
+    for tile in range(0u, tile_rows * WIDTH_TILES) {
+      let tile_data = self.buffer.slice(tile * TILE_BYTES, (tile + 1) * TILE_BYTES);
+      let base_x = (tile % WIDTH_TILES) * 8;
+      let base_y = (tile / WIDTH_TILES) * 8;
+      for row in range(0u, 8u) {
+        for col in range(0u, 8u) {
+          let low_bit  = (tile_data[2*row]   >> (7 - col)) & 1;
+          let high_bit = (tile_data[2*row+1] >> (7 - col)) & 1;
+          let value = (high_bit << 1) | low_bit;
+          let shade = (palette >> (2 * value as uint)) & 0b11;
+          *pixels.get_mut((base_y + row) * width + base_x + col) = SHADES[shade as uint];
+        }
+      }
+    }
+
+    let path = png::numbered_path(&self.path_base);
+    try!(png::write_grayscale(&path, width, height, pixels.as_slice()));
+    println!("Printed {}", path.display());
+    Ok(())
+  }
+}
+
+impl serial::Peer for Printer {
+  fn send(&mut self, byte: u8) {
+    let reply = self.receive(byte);
+    self.reply = Some(reply);
+  }
+
+  fn tick(&mut self, _cycles: u8, _waiting: Option<u8>) {
+    // The printer never drives the clock
+  }
+
+  fn take_reply(&mut self) -> Option<u8> {
+    self.reply.take()
+  }
+
+  fn take_clocked(&mut self) -> Option<u8> {
+    None
+  }
+}
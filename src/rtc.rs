@@ -0,0 +1,151 @@
+use cpu;
+
+//
+// Statics
+//
+
+const RTC_SECONDS: uint = 0;
+const RTC_MINUTES: uint = 1;
+const RTC_HOURS:   uint = 2;
+const RTC_DAY_LOW: uint = 3;
+const RTC_FLAGS:   uint = 4;
+
+const FLAG_DAY_HIGH: u8 = 0b0000_0001;
+const FLAG_HALT:     u8 = 0b0100_0000;
+const FLAG_CARRY:    u8 = 0b1000_0000;
+const FLAGS_MASK:    u8 = FLAG_DAY_HIGH | FLAG_HALT | FLAG_CARRY;
+
+pub const FOOTER_SIZE: uint = 48;
+const FOOTER_SIZE_32BIT: uint = 44; // Older variant with a 32-bit timestamp
+
+
+//
+// Real-Time Clock (MBC3)
+//
+
+pub struct Rtc {
+  regs: [u8, ..5],    // Live counter registers (0x08-0x0c)
+  latched: [u8, ..5], // Latched copy visible to the CPU
+  latch_armed: bool,  // Last write to the latch register was 0x00
+  cycles: uint,       // Accumulated cycles below one second
+}
+
+impl Rtc {
+  pub fn new() -> Rtc {
+    Rtc { regs: [0u8, ..5], latched: [0u8, ..5], latch_armed: false, cycles: 0 }
+  }
+
+  pub fn tick(&mut self, cycles: u8) {
+    if self.regs[RTC_FLAGS] & FLAG_HALT != 0 {
+      return;
+    }
+    self.cycles += cycles as uint;
+    if self.cycles >= cpu::CYCLES_PER_SEC {
+      self.cycles -= cpu::CYCLES_PER_SEC;
+      self.advance(1);
+    }
+  }
+
+  // Advances the clock by the given number of seconds, setting the day carry
+  // flag when the 9-bit day counter overflows.
+  pub fn advance(&mut self, seconds: u64) {
+    if self.regs[RTC_FLAGS] & FLAG_HALT != 0 {
+      return;
+    }
+
+    let days = self.regs[RTC_DAY_LOW] as u64 |
+               ((self.regs[RTC_FLAGS] & FLAG_DAY_HIGH) as u64 << 8);
+    let mut total = seconds + self.regs[RTC_SECONDS] as u64 +
+                    60 * (self.regs[RTC_MINUTES] as u64 +
+                          60 * (self.regs[RTC_HOURS] as u64 + 24 * days));
+
+    self.regs[RTC_SECONDS] = (total % 60) as u8;
+    total /= 60;
+    self.regs[RTC_MINUTES] = (total % 60) as u8;
+    total /= 60;
+    self.regs[RTC_HOURS] = (total % 24) as u8;
+    total /= 24;
+
+    let mut flags = self.regs[RTC_FLAGS] & !FLAG_DAY_HIGH;
+    if total > 0x1ff {
+      flags |= FLAG_CARRY;
+    }
+    let days = total & 0x1ff;
+    self.regs[RTC_DAY_LOW] = days as u8;
+    self.regs[RTC_FLAGS] = flags | (days >> 8) as u8;
+  }
+
+  // Writing 0x00 followed by 0x01 copies the live registers to the latch.
+  pub fn latch(&mut self, val: u8) {
+    if self.latch_armed && val == 0x01 {
+      self.latched = self.regs;
+    }
+    self.latch_armed = val == 0x00;
+  }
+
+  pub fn loadb(&self, reg: u8) -> u8 {
+    match reg {
+      0x08...0x0c => self.latched[(reg - 0x08) as uint],
+      _ => panic!("invalid RTC register: 0x{:02X}", reg),
+    }
+  }
+
+  pub fn storeb(&mut self, reg: u8, val: u8) {
+    let index = (reg - 0x08) as uint;
+    let masked =
+      match index {
+        RTC_SECONDS => { self.cycles = 0; val & 0x3f },
+        RTC_MINUTES => val & 0x3f,
+        RTC_HOURS   => val & 0x1f,
+        RTC_DAY_LOW => val,
+        RTC_FLAGS   => val & FLAGS_MASK,
+        _ => panic!("invalid RTC register: 0x{:02X}", reg),
+      };
+    self.regs[index] = masked;
+    self.latched[index] = masked;
+  }
+
+  // Serializes the clock in the footer format shared by VBA-M, BGB and others:
+  // live and latched registers as 32-bit little-endian words, followed by the
+  // 64-bit UNIX timestamp of the time of saving.
+  pub fn to_footer(&self, timestamp: i64) -> Vec<u8> {
+    let mut footer = Vec::with_capacity(FOOTER_SIZE);
+    for &reg in self.regs.iter().chain(self.latched.iter()) {
+      push_le(&mut footer, reg as u64, 4);
+    }
+    push_le(&mut footer, timestamp as u64, 8);
+    footer
+  }
+
+  // Restores the clock from a save file footer and returns the timestamp
+  // stored with it, or None if the footer is missing or truncated.
+  pub fn from_footer(&mut self, footer: &[u8]) -> Option<i64> {
+    if footer.len() != FOOTER_SIZE && footer.len() != FOOTER_SIZE_32BIT {
+      return None;
+    }
+
+    for i in range(0u, 5u) {
+      self.regs[i] = read_le(footer.slice(i * 4, i * 4 + 4)) as u8;
+      self.latched[i] = read_le(footer.slice(20 + i * 4, 24 + i * 4)) as u8;
+    }
+    self.regs[RTC_FLAGS] &= FLAGS_MASK;
+    self.latched[RTC_FLAGS] &= FLAGS_MASK;
+
+    let timestamp = read_le(footer.slice_from(40));
+    if footer.len() == FOOTER_SIZE_32BIT {
+      Some(timestamp as u32 as i64)
+    } else {
+      Some(timestamp as i64)
+    }
+  }
+}
+
+fn push_le(buf: &mut Vec<u8>, val: u64, bytes: uint) {
+  for i in range(0u, bytes) {
+    buf.push((val >> (8 * i)) as u8);
+  }
+}
+
+fn read_le(buf: &[u8]) -> u64 {
+  buf.iter().rev().fold(0u64, |acc, &b| (acc << 8) | b as u64)
+}
@@ -1,21 +1,118 @@
 use mem;
-use std::io::Writer;
 
 //
 // Serial I/O
 //
 
 const SERIAL_TRANSFER_FLAG: u8 = 0x80;
+const SERIAL_INTERNAL_CLOCK: u8 = 0x01;
+const SERIAL_UNUSED_BITS: u8 = 0x7e;
+
+const CYCLES_PER_BIT: uint = 512; // = 8,192 Hz
+
+// Device at the other end of the link cable
+pub trait Peer {
+  // Starts a transfer clocked by this side, sending a byte
+  fn send(&mut self, byte: u8);
+  // Advances the peer. `waiting` holds the byte to send back if this side is
+  // waiting for a transfer clocked by the peer.
+  fn tick(&mut self, cycles: u8, waiting: Option<u8>);
+  // Byte received in return for the last sent byte, once available
+  fn take_reply(&mut self) -> Option<u8>;
+  // Byte clocked in by the peer while this side was waiting
+  fn take_clocked(&mut self) -> Option<u8>;
+}
 
 pub struct SerialIO<'a> {
   data: u8, // SB register
   control: u8, // SC register
-  writer: Option<Box<Writer + 'a>>,
+  bits_left: uint, // Bits still to be shifted in the current transfer
+  bit_cycles: uint, // Accumulated cycles below CYCLES_PER_BIT
+  sent: u8, // Byte being shifted out
+  peer: Option<Box<Peer + 'a>>,
+}
+
+pub enum Signal {
+  TransferComplete
 }
 
 impl<'a> SerialIO<'a> {
-  pub fn new(writer: Option<Box<Writer + 'a>>) -> SerialIO<'a> {
-    SerialIO { data: 0, control: 0, writer: writer }
+  pub fn new() -> SerialIO<'a> {
+    SerialIO {
+      data: 0,
+      control: 0,
+      bits_left: 0,
+      bit_cycles: 0,
+      sent: 0,
+      peer: None,
+    }
+  }
+
+  pub fn set_peer(&mut self, peer: Box<Peer + 'a>) {
+    self.peer = Some(peer);
+  }
+
+  fn transferring(&self) -> bool {
+    (self.control & SERIAL_TRANSFER_FLAG) != 0
+  }
+
+  fn internal_clock(&self) -> bool {
+    (self.control & SERIAL_INTERNAL_CLOCK) != 0
+  }
+
+  fn finish_transfer(&mut self) -> Option<Signal> {
+    // Reset transfer flag to indicate transfer has finished
+    self.control &= !SERIAL_TRANSFER_FLAG;
+    Some(TransferComplete)
+  }
+
+  pub fn tick(&mut self, cycles: u8) -> Option<Signal> {
+    let waiting =
+      if self.transferring() && !self.internal_clock() { Some(self.data) } else { None };
+
+    match self.peer {
+      Some(ref mut peer) => peer.tick(cycles, waiting),
+      None => (),
+    }
+
+    if !self.transferring() {
+      return None;
+    }
+
+    if !self.internal_clock() {
+      // Externally clocked transfers wait for a peer
+      let clocked = self.peer.as_mut().and_then(|peer| peer.take_clocked());
+      return match clocked {
+        Some(byte) => {
+          self.sent = self.data;
+          self.data = byte;
+          self.finish_transfer()
+        },
+        None => None,
+      };
+    }
+
+    self.bit_cycles += cycles as uint;
+    while self.bit_cycles >= CYCLES_PER_BIT && self.bits_left > 0 {
+      self.bit_cycles -= CYCLES_PER_BIT;
+      // Shift in dummy bits, a peer provides the whole byte at the end
+      self.data = (self.data << 1) | 1;
+      self.bits_left -= 1;
+    }
+    if self.bits_left > 0 {
+      return None;
+    }
+
+    match self.peer {
+      Some(ref mut peer) => {
+        match peer.take_reply() {
+          Some(byte) => self.data = byte,
+          None => return None, // Not yet received
+        }
+      },
+      None => (), // No external GameBoy present, keep dummy value
+    }
+    self.finish_transfer()
   }
 }
 
@@ -23,7 +120,7 @@ impl<'a> mem::Mem for SerialIO<'a> {
   fn loadb(&mut self, addr: u16) -> u8 {
     match addr {
       0xff01 => self.data,
-      0xff02 => self.control,
+      0xff02 => self.control | SERIAL_UNUSED_BITS,
       _ => panic!("invalid serial I/O register"),
     }
   }
@@ -34,18 +131,16 @@ impl<'a> mem::Mem for SerialIO<'a> {
         self.data = val;
       }
       0xff02 => {
-        self.control = val;
-        if (val & SERIAL_TRANSFER_FLAG) != 0 {
+        self.control = val & !SERIAL_UNUSED_BITS;
+        if self.transferring() && self.internal_clock() {
           // Start transfer
-          // TODO: This should be done with 8192 bits per second
-          match self.writer {
-            Some(ref mut writer) => { let r = writer.write_u8(self.data); },
+          self.sent = self.data;
+          self.bits_left = 8;
+          self.bit_cycles = 0;
+          match self.peer {
+            Some(ref mut peer) => peer.send(self.sent),
             None => (),
           }
-          // No external GameBoy present, receive dummy value
-          self.data = 0xff;
-          // Reset transfer flag to indicate transfer has finished
-          self.control &= !SERIAL_TRANSFER_FLAG;
         }
       }
       _ => panic!("invalid serial I/O register"),
@@ -1,16 +1,729 @@
 use mem;
+use std::collections::RingBuf;
+
+//
+// Statics
+//
+
+const DUTY_PATTERNS: [[u8, ..8], ..4] = [
+  [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+  [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+  [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+  [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+const NOISE_DIVISORS: [uint, ..8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Bits that read back as 1 for each register in 0xff10-0xff2f
+const READ_MASKS: [u8, ..0x20] = [
+  0x80, 0x3f, 0x00, 0xff, 0xbf, // NR10-NR14
+  0xff, 0x3f, 0x00, 0xff, 0xbf, // NR20-NR24
+  0x7f, 0xff, 0x9f, 0xff, 0xbf, // NR30-NR34
+  0xff, 0xff, 0x00, 0x00, 0xbf, // NR40-NR44
+  0x00, 0x00, 0x70,             // NR50-NR52
+  0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, // unused
+];
+
+// The frame sequencer is clocked by the falling edge of this bit of the
+// 16-bit divider, i.e. bit 4 of DIV (512 Hz)
+const FRAME_SEQUENCER_DIV_BIT: u16 = 1 << 12;
+
+const NR52_POWER: u8 = 0b1000_0000;
+const NRX4_TRIGGER: u8 = 0b1000_0000;
+const NRX4_LENGTH_ENABLE: u8 = 0b0100_0000;
+
+const OUTPUT_SCALE: i16 = 64; // 4 channels * +-15 * 8 (volume) * 64 fits in i16
+
+pub const CHANNEL_COUNT: uint = 4;
+
+// Channel taps sample every 95 cycles (~44.1 kHz) and keep the latest samples
+const TAP_PERIOD: uint = 95;
+pub const TAP_SIZE: uint = 1024;
+
+
+//
+// Channel building blocks
+//
+
+struct Length {
+  counter: u16,
+  max: u16, // 64, or 256 for the wave channel
+  enabled: bool,
+}
+
+impl Length {
+  fn new(max: u16) -> Length {
+    Length { counter: 0, max: max, enabled: false }
+  }
+
+  fn load(&mut self, val: u8) {
+    self.counter = self.max - val as u16;
+  }
+
+  fn trigger(&mut self) {
+    if self.counter == 0 {
+      self.counter = self.max;
+    }
+  }
+
+  // Returns true when the counter expires and the channel must be disabled
+  fn clock(&mut self) -> bool {
+    if self.enabled && self.counter > 0 {
+      self.counter -= 1;
+      self.counter == 0
+    } else {
+      false
+    }
+  }
+}
+
+struct Envelope {
+  initial: u8,
+  increase: bool,
+  period: u8,
+  volume: u8,
+  timer: u8,
+}
+
+impl Envelope {
+  fn new() -> Envelope {
+    Envelope { initial: 0, increase: false, period: 0, volume: 0, timer: 0 }
+  }
+
+  fn write(&mut self, val: u8) {
+    self.initial = val >> 4;
+    self.increase = (val & 0b1000) != 0;
+    self.period = val & 0b111;
+  }
+
+  // The DAC is powered as long as any of the upper 5 bits of NRx2 is set
+  fn dac_enabled(&self) -> bool {
+    self.initial != 0 || self.increase
+  }
+
+  fn trigger(&mut self) {
+    self.volume = self.initial;
+    self.timer = self.period;
+  }
+
+  fn clock(&mut self) {
+    if self.period == 0 {
+      return;
+    }
+    if self.timer > 0 {
+      self.timer -= 1;
+    }
+    if self.timer == 0 {
+      self.timer = self.period;
+      if self.increase && self.volume < 15 {
+        self.volume += 1;
+      } else if !self.increase && self.volume > 0 {
+        self.volume -= 1;
+      }
+    }
+  }
+}
+
+
+//
+// Square channels (channel 1 with frequency sweep)
+//
+
+struct Square {
+  enabled: bool,
+  duty: u8,
+  duty_pos: uint,
+  freq: u16,
+  timer: int,
+  length: Length,
+  envelope: Envelope,
+
+  // Frequency sweep (channel 1 only)
+  sweep_period: u8,
+  sweep_negate: bool,
+  sweep_shift: u8,
+  sweep_timer: u8,
+  sweep_enabled: bool,
+  sweep_negated: bool, // A negate calculation happened since the last trigger
+  shadow_freq: u16,
+}
+
+impl Square {
+  fn new() -> Square {
+    Square {
+      enabled: false,
+      duty: 0,
+      duty_pos: 0,
+      freq: 0,
+      timer: 0,
+      length: Length::new(64),
+      envelope: Envelope::new(),
+      sweep_period: 0,
+      sweep_negate: false,
+      sweep_shift: 0,
+      sweep_timer: 0,
+      sweep_enabled: false,
+      sweep_negated: false,
+      shadow_freq: 0,
+    }
+  }
+
+  fn period(&self) -> int {
+    (2048 - self.freq as int) * 4
+  }
+
+  fn write_sweep(&mut self, val: u8) {
+    self.sweep_period = (val >> 4) & 0b111;
+    self.sweep_negate = (val & 0b1000) != 0;
+    self.sweep_shift = val & 0b111;
+    // Leaving negate mode after a negate calculation disables the channel
+    if !self.sweep_negate && self.sweep_negated {
+      self.enabled = false;
+    }
+  }
+
+  fn write_duty_length(&mut self, val: u8) {
+    self.duty = val >> 6;
+    self.length.load(val & 0x3f);
+  }
+
+  fn write_envelope(&mut self, val: u8) {
+    self.envelope.write(val);
+    if !self.envelope.dac_enabled() {
+      self.enabled = false;
+    }
+  }
+
+  fn write_freq_low(&mut self, val: u8) {
+    self.freq = (self.freq & 0x700) | val as u16;
+  }
+
+  fn write_freq_high(&mut self, val: u8) {
+    self.freq = (self.freq & 0xff) | ((val as u16 & 0b111) << 8);
+    self.length.enabled = (val & NRX4_LENGTH_ENABLE) != 0;
+    if (val & NRX4_TRIGGER) != 0 {
+      self.trigger();
+    }
+  }
+
+  fn trigger(&mut self) {
+    self.enabled = self.envelope.dac_enabled();
+    self.length.trigger();
+    self.timer = self.period();
+    self.envelope.trigger();
+
+    self.shadow_freq = self.freq;
+    self.sweep_timer = if self.sweep_period != 0 { self.sweep_period } else { 8 };
+    self.sweep_enabled = self.sweep_period != 0 || self.sweep_shift != 0;
+    self.sweep_negated = false;
+    if self.sweep_shift != 0 {
+      self.sweep_calc();
+    }
+  }
+
+  // Calculates the next sweep frequency, disabling the channel on overflow
+  fn sweep_calc(&mut self) -> u16 {
+    let delta = self.shadow_freq >> self.sweep_shift as uint;
+    let freq =
+      if self.sweep_negate {
+        self.sweep_negated = true;
+        self.shadow_freq - delta
+      } else {
+        self.shadow_freq + delta
+      };
+    if freq > 2047 {
+      self.enabled = false;
+    }
+    freq
+  }
+
+  fn clock_sweep(&mut self) {
+    if self.sweep_timer > 0 {
+      self.sweep_timer -= 1;
+    }
+    if self.sweep_timer != 0 {
+      return;
+    }
+    self.sweep_timer = if self.sweep_period != 0 { self.sweep_period } else { 8 };
+
+    if self.sweep_enabled && self.sweep_period != 0 {
+      let freq = self.sweep_calc();
+      if freq <= 2047 && self.sweep_shift != 0 {
+        self.freq = freq;
+        self.shadow_freq = freq;
+        self.sweep_calc(); // Overflow check only
+      }
+    }
+  }
+
+  fn clock_length(&mut self) {
+    if self.length.clock() {
+      self.enabled = false;
+    }
+  }
+
+  fn step(&mut self, cycles: uint) {
+    self.timer -= cycles as int;
+    while self.timer <= 0 {
+      self.timer += self.period();
+      self.duty_pos = (self.duty_pos + 1) % 8;
+    }
+  }
+
+  fn output(&self) -> u8 {
+    if self.enabled {
+      DUTY_PATTERNS[self.duty as uint][self.duty_pos] * self.envelope.volume
+    } else {
+      0
+    }
+  }
+}
+
+
+//
+// Wave channel
+//
+
+struct Wave {
+  enabled: bool,
+  dac_enabled: bool,
+  volume_shift: u8, // NR32 volume code
+  freq: u16,
+  timer: int,
+  position: uint,
+  length: Length,
+  ram: [u8, ..16], // 32 4-bit samples
+}
+
+impl Wave {
+  fn new() -> Wave {
+    Wave {
+      enabled: false,
+      dac_enabled: false,
+      volume_shift: 0,
+      freq: 0,
+      timer: 0,
+      position: 0,
+      length: Length::new(256),
+      ram: [0u8, ..16],
+    }
+  }
+
+  fn period(&self) -> int {
+    (2048 - self.freq as int) * 2
+  }
+
+  fn write_dac(&mut self, val: u8) {
+    self.dac_enabled = (val & 0x80) != 0;
+    if !self.dac_enabled {
+      self.enabled = false;
+    }
+  }
+
+  fn write_freq_high(&mut self, val: u8) {
+    self.freq = (self.freq & 0xff) | ((val as u16 & 0b111) << 8);
+    self.length.enabled = (val & NRX4_LENGTH_ENABLE) != 0;
+    if (val & NRX4_TRIGGER) != 0 {
+      self.enabled = self.dac_enabled;
+      self.length.trigger();
+      self.timer = self.period();
+      self.position = 0;
+    }
+  }
+
+  fn clock_length(&mut self) {
+    if self.length.clock() {
+      self.enabled = false;
+    }
+  }
+
+  fn step(&mut self, cycles: uint) {
+    self.timer -= cycles as int;
+    while self.timer <= 0 {
+      self.timer += self.period();
+      self.position = (self.position + 1) % 32;
+    }
+  }
+
+  fn output(&self) -> u8 {
+    if !self.enabled || self.volume_shift == 0 {
+      return 0;
+    }
+    let byte = self.ram[self.position / 2];
+    let sample = if self.position % 2 == 0 { byte >> 4 } else { byte & 0x0f };
+    sample >> (self.volume_shift - 1) as uint
+  }
+}
+
+
+//
+// Noise channel
+//
+
+struct Noise {
+  enabled: bool,
+  clock_shift: u8,
+  width_mode: bool, // 7-bit LFSR
+  divisor_code: u8,
+  timer: int,
+  lfsr: u16,
+  length: Length,
+  envelope: Envelope,
+}
+
+impl Noise {
+  fn new() -> Noise {
+    Noise {
+      enabled: false,
+      clock_shift: 0,
+      width_mode: false,
+      divisor_code: 0,
+      timer: 0,
+      lfsr: 0x7fff,
+      length: Length::new(64),
+      envelope: Envelope::new(),
+    }
+  }
+
+  fn period(&self) -> int {
+    (NOISE_DIVISORS[self.divisor_code as uint] << self.clock_shift as uint) as int
+  }
+
+  fn write_envelope(&mut self, val: u8) {
+    self.envelope.write(val);
+    if !self.envelope.dac_enabled() {
+      self.enabled = false;
+    }
+  }
+
+  fn write_polynomial(&mut self, val: u8) {
+    self.clock_shift = val >> 4;
+    self.width_mode = (val & 0b1000) != 0;
+    self.divisor_code = val & 0b111;
+  }
+
+  fn write_control(&mut self, val: u8) {
+    self.length.enabled = (val & NRX4_LENGTH_ENABLE) != 0;
+    if (val & NRX4_TRIGGER) != 0 {
+      self.enabled = self.envelope.dac_enabled();
+      self.length.trigger();
+      self.timer = self.period();
+      self.envelope.trigger();
+      self.lfsr = 0x7fff;
+    }
+  }
+
+  fn clock_length(&mut self) {
+    if self.length.clock() {
+      self.enabled = false;
+    }
+  }
+
+  fn step(&mut self, cycles: uint) {
+    self.timer -= cycles as int;
+    while self.timer <= 0 {
+      self.timer += self.period();
+      let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+      self.lfsr = (self.lfsr >> 1) | (bit << 14);
+      if self.width_mode {
+        self.lfsr = (self.lfsr & !(1 << 6)) | (bit << 6);
+      }
+    }
+  }
+
+  fn output(&self) -> u8 {
+    if self.enabled && (self.lfsr & 1) == 0 {
+      self.envelope.volume
+    } else {
+      0
+    }
+  }
+}
+
 
 //
 // Sound
 //
 
-pub struct Sound;
+pub struct Sound {
+  regs: [u8, ..0x20], // Raw register values for 0xff10-0xff2f
+  powered: bool,      // NR52 bit 7
+
+  square1: Square,
+  square2: Square,
+  wave: Wave,
+  noise: Noise,
+
+  frame_step: u8,     // Frame sequencer step (0-7)
+  div_bit: bool,      // Last state of the frame sequencer DIV bit
+
+  // Debugging aids
+  muted: [bool, ..CHANNEL_COUNT],
+  solo: Option<uint>,
+  taps: Option<Vec<RingBuf<i8>>>, // Per-channel DAC output history
+  tap_cycles: uint,
+}
+
+impl Sound {
+  pub fn new() -> Sound {
+    Sound {
+      regs: [0u8, ..0x20],
+      powered: false,
+      square1: Square::new(),
+      square2: Square::new(),
+      wave: Wave::new(),
+      noise: Noise::new(),
+      frame_step: 0,
+      div_bit: false,
+      muted: [false, ..CHANNEL_COUNT],
+      solo: None,
+      taps: None,
+      tap_cycles: 0,
+    }
+  }
+
+  pub fn is_muted(&self, channel: uint) -> bool {
+    self.muted[channel]
+  }
+
+  pub fn toggle_mute(&mut self, channel: uint) -> bool {
+    self.muted[channel] = !self.muted[channel];
+    self.muted[channel]
+  }
+
+  pub fn solo(&self) -> Option<uint> {
+    self.solo
+  }
+
+  // Solos a channel, or restores all channels if it was already soloed
+  pub fn toggle_solo(&mut self, channel: uint) -> Option<uint> {
+    self.solo = if self.solo == Some(channel) { None } else { Some(channel) };
+    self.solo
+  }
+
+  pub fn clear_solo(&mut self) {
+    self.solo = None;
+  }
+
+  fn audible(&self, channel: uint) -> bool {
+    match self.solo {
+      Some(solo) => channel == solo,
+      None => !self.muted[channel],
+    }
+  }
+
+  // Starts or stops recording per-channel output for oscilloscope views
+  pub fn set_tap_enabled(&mut self, enabled: bool) {
+    self.taps =
+      if enabled {
+        Some(Vec::from_fn(CHANNEL_COUNT, |_| RingBuf::with_capacity(TAP_SIZE)))
+      } else {
+        None
+      };
+    self.tap_cycles = 0;
+  }
+
+  // Latest DAC output samples (-15..15) of a channel, oldest first
+  pub fn tap(&self, channel: uint) -> Option<&RingBuf<i8>> {
+    self.taps.as_ref().map(|taps| &taps[channel])
+  }
+
+  fn update_taps(&mut self, cycles: uint) {
+    if self.taps.is_none() {
+      return;
+    }
+    self.tap_cycles += cycles;
+    while self.tap_cycles >= TAP_PERIOD {
+      self.tap_cycles -= TAP_PERIOD;
+      let levels = self.analog_outputs();
+      for (tap, &level) in self.taps.as_mut().unwrap().iter_mut().zip(levels.iter()) {
+        if tap.len() == TAP_SIZE {
+          tap.pop_front();
+        }
+        tap.push_back(level as i8);
+      }
+    }
+  }
+
+  // Advances the sound unit. The frame sequencer is driven by the timer's
+  // 16-bit divider, so writes to DIV affect it like on real hardware.
+  pub fn tick(&mut self, cycles: u8, div: u16) {
+    let div_bit = (div & FRAME_SEQUENCER_DIV_BIT) != 0;
+    if self.powered && self.div_bit && !div_bit {
+      self.clock_frame_sequencer();
+    }
+    self.div_bit = div_bit;
+
+    if !self.powered {
+      return;
+    }
+
+    let cycles = cycles as uint;
+    self.square1.step(cycles);
+    self.square2.step(cycles);
+    self.wave.step(cycles);
+    self.noise.step(cycles);
+
+    self.update_taps(cycles);
+  }
+
+  fn clock_frame_sequencer(&mut self) {
+    // Length counters at 256 Hz, sweep at 128 Hz, envelopes at 64 Hz
+    match self.frame_step {
+      0 | 4 => self.clock_lengths(),
+      2 | 6 => { self.clock_lengths(); self.square1.clock_sweep(); },
+      7 => {
+        self.square1.envelope.clock();
+        self.square2.envelope.clock();
+        self.noise.envelope.clock();
+      },
+      _ => (),
+    }
+    self.frame_step = (self.frame_step + 1) % 8;
+  }
+
+  fn clock_lengths(&mut self) {
+    self.square1.clock_length();
+    self.square2.clock_length();
+    self.wave.clock_length();
+    self.noise.clock_length();
+  }
+
+  // Digital output (0-15) of each channel
+  pub fn channel_outputs(&self) -> [u8, ..CHANNEL_COUNT] {
+    [self.square1.output(), self.square2.output(), self.wave.output(), self.noise.output()]
+  }
+
+  fn dac_enabled(&self, channel: uint) -> bool {
+    match channel {
+      0 => self.square1.envelope.dac_enabled(),
+      1 => self.square2.envelope.dac_enabled(),
+      2 => self.wave.dac_enabled,
+      _ => self.noise.envelope.dac_enabled(),
+    }
+  }
+
+  // Analog output of each channel's DAC (-15..15, 0 if the DAC is off)
+  fn analog_outputs(&self) -> [i16, ..CHANNEL_COUNT] {
+    let outputs = self.channel_outputs();
+    let mut analog = [0i16, ..CHANNEL_COUNT];
+    for (channel, &out) in outputs.iter().enumerate() {
+      if self.dac_enabled(channel) {
+        // The DAC maps 0-15 to a symmetric analog range
+        analog[channel] = out as i16 * 2 - 15;
+      }
+    }
+    analog
+  }
+
+  // Mixed stereo output (left, right) after NR51 panning and NR50 volume
+  pub fn output(&self) -> (i16, i16) {
+    if !self.powered {
+      return (0, 0);
+    }
+
+    let nr50 = self.regs[0x14];
+    let nr51 = self.regs[0x15];
+    let outputs = self.analog_outputs();
+
+    let mut left = 0i16;
+    let mut right = 0i16;
+    for (channel, &analog) in outputs.iter().enumerate() {
+      if !self.audible(channel) {
+        continue;
+      }
+      if (nr51 & (0x10 << channel)) != 0 {
+        left += analog;
+      }
+      if (nr51 & (0x01 << channel)) != 0 {
+        right += analog;
+      }
+    }
+
+    let left_volume = ((nr50 >> 4) & 0b111) as i16 + 1;
+    let right_volume = (nr50 & 0b111) as i16 + 1;
+    (left * left_volume * OUTPUT_SCALE, right * right_volume * OUTPUT_SCALE)
+  }
+
+  fn power_off(&mut self) {
+    // All registers except wave RAM are cleared
+    self.regs = [0u8, ..0x20];
+    let wave_ram = self.wave.ram;
+    self.square1 = Square::new();
+    self.square2 = Square::new();
+    self.wave = Wave::new();
+    self.wave.ram = wave_ram;
+    self.noise = Noise::new();
+  }
+
+  fn nr52(&self) -> u8 {
+    let mut status = if self.powered { NR52_POWER } else { 0 };
+    if self.square1.enabled { status |= 0b0001 }
+    if self.square2.enabled { status |= 0b0010 }
+    if self.wave.enabled    { status |= 0b0100 }
+    if self.noise.enabled   { status |= 0b1000 }
+    status
+  }
+}
 
 impl mem::Mem for Sound {
   fn loadb(&mut self, addr: u16) -> u8 {
-    0
+    match addr {
+      0xff26 => self.nr52() | READ_MASKS[0x16],
+      0xff10...0xff2f => self.regs[(addr - 0xff10) as uint] | READ_MASKS[(addr - 0xff10) as uint],
+      0xff30...0xff3f => self.wave.ram[(addr - 0xff30) as uint],
+      _ => panic!("invalid sound address: ${:04X}", addr),
+    }
   }
 
   fn storeb(&mut self, addr: u16, val: u8) {
+    match addr {
+      0xff26 => {
+        let powered = (val & NR52_POWER) != 0;
+        if self.powered && !powered {
+          self.power_off();
+        } else if !self.powered && powered {
+          self.frame_step = 0;
+        }
+        self.powered = powered;
+        return;
+      },
+      0xff30...0xff3f => {
+        self.wave.ram[(addr - 0xff30) as uint] = val;
+        return;
+      },
+      0xff10...0xff2f if !self.powered => {
+        // Registers are read-only while powered off
+        return;
+      },
+      0xff10...0xff2f => self.regs[(addr - 0xff10) as uint] = val,
+      _ => panic!("invalid sound address: ${:04X}", addr),
+    }
+
+    match addr {
+      0xff10 => self.square1.write_sweep(val),
+      0xff11 => self.square1.write_duty_length(val),
+      0xff12 => self.square1.write_envelope(val),
+      0xff13 => self.square1.write_freq_low(val),
+      0xff14 => self.square1.write_freq_high(val),
+
+      0xff16 => self.square2.write_duty_length(val),
+      0xff17 => self.square2.write_envelope(val),
+      0xff18 => self.square2.write_freq_low(val),
+      0xff19 => self.square2.write_freq_high(val),
+
+      0xff1a => self.wave.write_dac(val),
+      0xff1b => self.wave.length.load(val),
+      0xff1c => self.wave.volume_shift = (val >> 5) & 0b11,
+      0xff1d => self.wave.freq = (self.wave.freq & 0x700) | val as u16,
+      0xff1e => self.wave.write_freq_high(val),
+
+      0xff20 => self.noise.length.load(val & 0x3f),
+      0xff21 => self.noise.write_envelope(val),
+      0xff22 => self.noise.write_polynomial(val),
+      0xff23 => self.noise.write_control(val),
+
+      _ => (), // NR50/NR51 are evaluated by the mixer, others are unused
+    }
   }
 }
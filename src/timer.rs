@@ -5,27 +5,33 @@ use mem;
 //
 
 const DIV_CYCLE_SHIFT: uint = 8;
-const TIMA_CYCLE_SHIFT: &'static [u16] = &[
-  10,  // =   4,096 Hz
-  4,   // = 262,144 Hz
-  6,   // =  65,536 Hz
-  8,   // =  16,384 Hz
+const TIMA_INPUT_BIT: &'static [uint] = &[
+  9,  // =   4,096 Hz
+  3,  // = 262,144 Hz
+  5,  // =  65,536 Hz
+  7,  // =  16,384 Hz
 ];
 
 const TIMER_START_FLAG: u8 = 0x04;
 const TIMER_INPUT_CLOCK_MASK: u8 = 0x03;
+const TAC_UNUSED_BITS: u8 = 0xf8;
+
+const MACHINE_CYCLE: u16 = 4;
 
 
 //
 // Timer
 //
 
+// TIMA counts falling edges of (TAC enable AND a bit of the internal
+// divider), so resetting DIV or rewriting TAC can increment it as well.
 pub struct Timer {
   div_cycles: u16, // Increments each cycle, actual register value is high byte (clock divider 256)
   tima: u8, // Actual register value
-  tima_cycles_mod: u16, // Accumulated cycles below current TIMA_CYCLES
   tma: u8,
   tac: u8,
+  overflow: bool,  // TIMA overflowed during the last machine cycle, reload is pending
+  reloaded: bool,  // TIMA was reloaded from TMA during the last machine cycle
 }
 
 pub enum Signal {
@@ -34,27 +40,51 @@ pub enum Signal {
 
 impl Timer {
   pub fn new() -> Timer {
-    Timer { div_cycles: 0, tima: 0, tima_cycles_mod: 0, tma: 0, tac: 0 }
+    Timer { div_cycles: 0, tima: 0, tma: 0, tac: 0, overflow: false, reloaded: false }
+  }
+
+  // Internal 16-bit divider, whose upper byte is the DIV register
+  pub fn div(&self) -> u16 {
+    self.div_cycles
+  }
+
+  // State of the signal whose falling edges increment TIMA
+  fn input(&self) -> bool {
+    let bit = TIMA_INPUT_BIT[(self.tac & TIMER_INPUT_CLOCK_MASK) as uint];
+    (self.tac & TIMER_START_FLAG) != 0 && (self.div_cycles & (1 << bit)) != 0
+  }
+
+  fn detect_edge(&mut self, old_input: bool) {
+    if old_input && !self.input() {
+      self.increment();
+    }
   }
 
+  fn increment(&mut self) {
+    if self.tima == 0xff {
+      // TIMA reads 0 for one machine cycle before being reloaded from TMA
+      self.tima = 0;
+      self.overflow = true;
+    } else {
+      self.tima += 1;
+    }
+  }
+
+  // Advances the timer; cycles are always a multiple of a machine cycle
   pub fn tick(&mut self, cycles: u8) -> Option<Signal> {
     let mut result = None;
-    self.div_cycles += cycles as u16;
-    if (self.tac & TIMER_START_FLAG) != 0 {
-      self.tima_cycles_mod += cycles as u16;
-      let shift = TIMA_CYCLE_SHIFT[(self.tac & TIMER_INPUT_CLOCK_MASK) as uint] as uint;
-      let increment = self.tima_cycles_mod >> shift;
-      if increment != 0 {
-        // TIMA must be incremented
-        if self.tima as u16 + increment > 0xff {
-          // Overflow, reset to TMA
-          self.tima = self.tma;
-          result = Some(TIMAOverflow);
-        } else {
-          self.tima += increment as u8;
-        }
-        self.tima_cycles_mod -= increment << shift;
+    for _ in range(0, cycles as u16 / MACHINE_CYCLE) {
+      self.reloaded = false;
+      if self.overflow {
+        self.overflow = false;
+        self.tima = self.tma;
+        self.reloaded = true;
+        result = Some(TIMAOverflow);
       }
+
+      let old_input = self.input();
+      self.div_cycles += MACHINE_CYCLE;
+      self.detect_edge(old_input);
     }
     result
   }
@@ -66,17 +96,38 @@ impl mem::Mem for Timer {
       0xff04 => (self.div_cycles >> DIV_CYCLE_SHIFT) as u8, // DIV register
       0xff05 => self.tima,                                  // TIMA register
       0xff06 => self.tma,                                   // TMA register
-      0xff07 => self.tac,                                   // TAC register
+      0xff07 => self.tac | TAC_UNUSED_BITS,                 // TAC register
       _ => panic!("invalid timer register"),
     }
   }
 
   fn storeb(&mut self, addr: u16, val: u8) {
     match addr {
-      0xff04 => self.div_cycles = 0, // any value resets the register to 0
-      0xff05 => self.tima = val,     // TODO: is this correct?
-      0xff06 => self.tma = val,
-      0xff07 => { self.tac = val; self.tima_cycles_mod = 0 },
+      0xff04 => {
+        // Any value resets the whole divider to 0
+        let old_input = self.input();
+        self.div_cycles = 0;
+        self.detect_edge(old_input);
+      },
+      0xff05 => {
+        // Writes during the reload cycle are overridden by TMA, while writes
+        // in the cycle before cancel the pending reload (and interrupt)
+        if !self.reloaded {
+          self.tima = val;
+          self.overflow = false;
+        }
+      },
+      0xff06 => {
+        self.tma = val;
+        if self.reloaded {
+          self.tima = val;
+        }
+      },
+      0xff07 => {
+        let old_input = self.input();
+        self.tac = val & !TAC_UNUSED_BITS;
+        self.detect_edge(old_input);
+      },
       _ => panic!("invalid timer register"),
     }
   }
@@ -0,0 +1,194 @@
+use joypad;
+
+//
+// Turbo buttons and input macros
+//
+// Both advance once per emulated frame, so they press the same buttons on the
+// same frames regardless of host speed. Controls merges them with normal
+// presses into the state of each joypad button.
+//
+
+pub const DEFAULT_TURBO_RATE: uint = 2;
+
+// Toggles held turbo buttons, pressed for `rate` frames and released for
+// `rate` frames
+pub struct Turbo {
+  rate: uint,
+  held: Vec<joypad::Button>,
+  frame: uint,
+}
+
+impl Turbo {
+  pub fn new(rate: uint) -> Turbo {
+    Turbo { rate: if rate == 0 { 1 } else { rate }, held: vec!(), frame: 0 }
+  }
+
+  // Starts or stops auto-firing a button
+  pub fn set(&mut self, button: joypad::Button, held: bool) {
+    self.held.retain(|&b| b != button);
+    if held {
+      self.held.push(button);
+      if self.held.len() == 1 {
+        self.frame = 0;
+      }
+    }
+  }
+
+  pub fn is_pressed(&self, button: joypad::Button) -> bool {
+    self.held.contains(&button) && (self.frame / self.rate) % 2 == 0
+  }
+
+  // Advances by one frame. Returns the buttons that may have changed.
+  pub fn frame(&mut self) -> Vec<joypad::Button> {
+    if self.held.len() == 0 {
+      return vec!();
+    }
+    self.frame += 1;
+    self.held.clone()
+  }
+}
+
+// Buttons held for a number of frames
+#[deriving(Clone)]
+pub struct MacroStep {
+  pub buttons: Vec<joypad::Button>,
+  pub frames: uint,
+}
+
+pub fn parse_button(name: &str) -> Option<joypad::Button> {
+  match name {
+    "up"     => Some(joypad::Up),
+    "down"   => Some(joypad::Down),
+    "left"   => Some(joypad::Left),
+    "right"  => Some(joypad::Right),
+    "a"      => Some(joypad::ButtonA),
+    "b"      => Some(joypad::ButtonB),
+    "start"  => Some(joypad::Start),
+    "select" => Some(joypad::Select),
+    _ => None,
+  }
+}
+
+// Parses a macro of the form "start:2, -:30, a+up:4", i.e. comma-separated
+// steps of buttons joined by '+' ('-' for none) and a frame count.
+pub fn parse_macro(s: &str) -> Option<Vec<MacroStep>> {
+  let mut steps = vec!();
+  for step in s.split(',') {
+    let parts = step.trim().split(':').collect::<Vec<&str>>();
+    if parts.len() != 2 {
+      return None;
+    }
+    let frames = match from_str::<uint>(parts[1].trim()) {
+      Some(frames) if frames > 0 => frames,
+      _ => return None,
+    };
+
+    let mut buttons = vec!();
+    let names = parts[0].trim();
+    if names != "-" {
+      for name in names.split('+') {
+        match parse_button(name.trim()) {
+          Some(button) => buttons.push(button),
+          None => return None,
+        }
+      }
+    }
+    steps.push(MacroStep { buttons: buttons, frames: frames });
+  }
+  Some(steps)
+}
+
+// Plays back one macro at a time
+pub struct MacroPlayer {
+  steps: Vec<MacroStep>,
+  step: uint,                   // Next step to start
+  frames_left: uint,            // Frames left in the current step
+  pressed: Vec<joypad::Button>, // Buttons held by the current step
+}
+
+impl MacroPlayer {
+  pub fn new() -> MacroPlayer {
+    MacroPlayer { steps: vec!(), step: 0, frames_left: 0, pressed: vec!() }
+  }
+
+  // Starts a macro on the next frame, cutting short any running one
+  pub fn start(&mut self, steps: &[MacroStep]) {
+    self.steps = steps.to_vec();
+    self.step = 0;
+    self.frames_left = 0;
+  }
+
+  pub fn is_pressed(&self, button: joypad::Button) -> bool {
+    self.pressed.contains(&button)
+  }
+
+  // Advances by one frame. Returns the buttons that may have changed.
+  pub fn frame(&mut self) -> Vec<joypad::Button> {
+    let mut changes = vec!();
+    if self.frames_left == 0 {
+      // Release the buttons of the finished step
+      changes.push_all(self.pressed.as_slice());
+      self.pressed.clear();
+      if self.step >= self.steps.len() {
+        return changes;
+      }
+
+      self.pressed = self.steps[self.step].buttons.clone();
+      self.frames_left = self.steps[self.step].frames;
+      self.step += 1;
+      changes.push_all(self.pressed.as_slice());
+    }
+    self.frames_left -= 1;
+    changes
+  }
+}
+
+// Buttons of one joypad, pressed through normal bindings, turbo or a macro.
+// A button stays pressed as long as any of them holds it.
+pub struct Controls {
+  held: [bool, ..8], // Pressed through normal bindings, by joypad::Button
+  turbo: Turbo,
+  macro_player: MacroPlayer,
+}
+
+impl Controls {
+  pub fn new(turbo_rate: uint) -> Controls {
+    Controls {
+      held: [false, ..8],
+      turbo: Turbo::new(turbo_rate),
+      macro_player: MacroPlayer::new(),
+    }
+  }
+
+  pub fn is_pressed(&self, button: joypad::Button) -> bool {
+    self.held[button as uint] || self.turbo.is_pressed(button) || self.macro_player.is_pressed(button)
+  }
+
+  // Buttons currently pressed through any source
+  pub fn pressed(&self) -> Vec<joypad::Button> {
+    joypad::BUTTONS.iter().map(|&b| b).filter(|&b| self.is_pressed(b)).collect()
+  }
+
+  // Updates a normal binding. Returns the button state to set.
+  pub fn set_held(&mut self, button: joypad::Button, held: bool) -> bool {
+    self.held[button as uint] = held;
+    self.is_pressed(button)
+  }
+
+  // Updates a turbo binding. Returns the button state to set.
+  pub fn set_turbo(&mut self, button: joypad::Button, held: bool) -> bool {
+    self.turbo.set(button, held);
+    self.is_pressed(button)
+  }
+
+  pub fn start_macro(&mut self, steps: &[MacroStep]) {
+    self.macro_player.start(steps);
+  }
+
+  // Advances turbo and macros by one frame. Returns the button states to set.
+  pub fn frame(&mut self) -> Vec<(joypad::Button, bool)> {
+    let mut changed = self.turbo.frame();
+    changed.push_all(self.macro_player.frame().as_slice());
+    changed.iter().map(|&button| (button, self.is_pressed(button))).collect()
+  }
+}
@@ -0,0 +1,131 @@
+use cpu;
+use std::io::{File, IoError, IoResult, SeekSet};
+
+//
+// Statics
+//
+
+const VGM_VERSION: u32 = 0x0000_0161; // First version with Game Boy DMG support
+const VGM_SAMPLE_RATE: u64 = 44100;
+const HEADER_SIZE: uint = 0x100;
+const WRITE_SIZE: uint = 0x10000; // Commands are written to the file in chunks of this size
+
+const CMD_GB_DMG_WRITE: u8 = 0xb3;
+const CMD_WAIT: u8 = 0x61;
+const CMD_WAIT_NTSC: u8 = 0x62; // 735 samples
+const CMD_WAIT_PAL: u8 = 0x63;  // 882 samples
+const CMD_WAIT_SHORT: u8 = 0x70; // 0x70-0x7f: 1-16 samples
+const CMD_END: u8 = 0x66;
+
+
+//
+// VGM Sound Log
+//
+
+// Logs writes to the sound registers as a VGM file, timed by CPU cycles.
+pub struct VgmWriter {
+  file: File,
+  data: Vec<u8>,      // Commands not yet written to the file
+  data_size: uint,    // Size of the commands already written
+  error: Option<IoError>, // First failed write, reported by finish()
+  cycles: u64,        // CPU cycles at the start of the current instruction
+  samples: u64,       // Samples (at 44.1 kHz) covered by the command stream
+}
+
+impl VgmWriter {
+  // Creates the file right away, so that an unusable path is reported before
+  // anything is logged. The header is filled in by finish().
+  pub fn create(path: &Path) -> IoResult<VgmWriter> {
+    let mut file = try!(File::create(path));
+    try!(file.write(Vec::from_elem(HEADER_SIZE, 0u8).as_slice()));
+    Ok(VgmWriter {
+      file: file,
+      data: vec!(),
+      data_size: 0,
+      error: None,
+      cycles: 0,
+      samples: 0,
+    })
+  }
+
+  pub fn set_cycles(&mut self, cycles: u64) {
+    self.cycles = cycles;
+  }
+
+  pub fn write(&mut self, addr: u16, val: u8) {
+    let now = self.cycles * VGM_SAMPLE_RATE / cpu::CYCLES_PER_SEC as u64;
+    self.wait(now - self.samples);
+    self.samples = now;
+
+    self.data.push(CMD_GB_DMG_WRITE);
+    self.data.push((addr - 0xff10) as u8);
+    self.data.push(val);
+    if self.data.len() >= WRITE_SIZE {
+      self.write_data();
+    }
+  }
+
+  fn write_data(&mut self) {
+    if self.error.is_none() {
+      match self.file.write(self.data.as_slice()) {
+        Ok(()) => self.data_size += self.data.len(),
+        Err(e) => self.error = Some(e),
+      }
+    }
+    self.data.clear();
+  }
+
+  fn wait(&mut self, samples: u64) {
+    let mut remaining = samples;
+    while remaining > 0 {
+      match remaining {
+        1...16 => {
+          self.data.push(CMD_WAIT_SHORT + (remaining - 1) as u8);
+          remaining = 0;
+        },
+        735 => { self.data.push(CMD_WAIT_NTSC); remaining = 0; },
+        882 => { self.data.push(CMD_WAIT_PAL); remaining = 0; },
+        _ => {
+          let n = if remaining > 0xffff { 0xffff } else { remaining };
+          self.data.push(CMD_WAIT);
+          self.data.push(n as u8);
+          self.data.push((n >> 8) as u8);
+          remaining -= n;
+        }
+      }
+    }
+  }
+
+  // Ends the log at the given cycle count and writes the header.
+  pub fn finish(&mut self, cycles: u64) -> IoResult<()> {
+    let end = cycles * VGM_SAMPLE_RATE / cpu::CYCLES_PER_SEC as u64;
+    if end > self.samples {
+      self.wait(end - self.samples);
+      self.samples = end;
+    }
+    self.data.push(CMD_END);
+    self.write_data();
+    match self.error.take() {
+      Some(e) => return Err(e),
+      None => (),
+    }
+
+    let mut header = Vec::from_elem(HEADER_SIZE, 0u8);
+    let file_size = HEADER_SIZE + self.data_size;
+    write_le32(&mut header, 0x00, 0x206d6756);  // "Vgm "
+    write_le32(&mut header, 0x04, (file_size - 0x04) as u32); // EOF offset
+    write_le32(&mut header, 0x08, VGM_VERSION);
+    write_le32(&mut header, 0x18, self.samples as u32); // Total samples
+    write_le32(&mut header, 0x34, (HEADER_SIZE - 0x34) as u32); // Data offset
+    write_le32(&mut header, 0x80, cpu::CYCLES_PER_SEC as u32); // GB DMG clock
+
+    try!(self.file.seek(0, SeekSet));
+    self.file.write(header.as_slice())
+  }
+}
+
+fn write_le32(buf: &mut Vec<u8>, pos: uint, val: u32) {
+  for i in range(0u, 4u) {
+    *buf.get_mut(pos + i) = (val >> (8 * i)) as u8;
+  }
+}
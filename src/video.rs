@@ -121,9 +121,22 @@ impl Video {
     }
   }
 
+  pub fn is_enabled(&self) -> bool {
+    (self.flags & FLAG_ENABLE) != 0
+  }
+
   pub fn tick(&mut self, cycles: u8) -> Vec<Signal> {
     let mut signals = vec!();
 
+    if !self.is_enabled() {
+      // The LCD stays at the start of the first row while switched off
+      if self.dma != 0xff {
+        signals.push(DMA(self.dma));
+        self.dma = 0xff;
+      }
+      return signals;
+    }
+
     let old_ly = self.ly;
     let old_mode = self.mode;
 
@@ -372,7 +385,24 @@ impl mem::Mem for Video {
       0xfe00...0xfe9f => self.oam[(addr - 0xfe00) as uint] = val,
 
       // I/O registers
-      0xff40 => self.flags = val,
+      0xff40 => {
+        let was_enabled = self.is_enabled();
+        self.flags = val;
+        if was_enabled && !self.is_enabled() {
+          self.cycles = 0;
+          self.ly = 0;
+          self.mode = 0;
+          self.update_stat();
+          // The screen shows white while the LCD is off
+          for pixel in self.screen.chunks_mut(4) {
+            pixel[0] = COLORS[0][2];
+            pixel[1] = COLORS[0][1];
+            pixel[2] = COLORS[0][0];
+          }
+        } else if !was_enabled && self.is_enabled() {
+          self.wy_saved = self.wy;
+        }
+      },
       0xff41 => self.stat = (val & STAT_IRQ_MASK) | (self.stat & !STAT_IRQ_MASK), // Only interrupt enable bits are writeable
       0xff42 => self.scy = val,
       0xff43 => self.scx = val,
@@ -389,19 +419,20 @@ impl mem::Mem for Video {
   }
 }
 
+// Shades of green (RGB), from lightest to darkest
+static COLORS: &'static [& 'static[u8]] = &[
+  &[224, 248, 208],
+  &[136, 192, 112],
+  &[52, 104, 86],
+  &[8, 24, 32]
+];
+
 fn unpack_tile_pixel(tile: &[u8],
                      palette: u8,
                      x: uint,
                      y: uint,
                      pixel: &mut [u8],
                      transp: bool) {
-  static COLORS: &'static [& 'static[u8]] = &[
-    &[224, 248, 208],
-    &[136, 192, 112],
-    &[52, 104, 86],
-    &[8, 24, 32]
-  ];
-
   let low_bit  = (tile[2*y]   >> (7 - x)) & 1;
   let high_bit = (tile[2*y+1] >> (7 - x)) & 1;
   let value = (high_bit << 1) | low_bit;
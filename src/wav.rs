@@ -0,0 +1,117 @@
+use audio;
+use std::io::{File, IoResult, SeekSet};
+
+//
+// WAV Output
+//
+
+const HEADER_SIZE: u32 = 44;
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+
+pub struct WavWriter {
+  file: File,
+  sample_rate: u32,
+  data_bytes: u32,
+  finished: bool, // Header holds the final sizes
+}
+
+impl WavWriter {
+  pub fn create(path: &Path, sample_rate: uint) -> IoResult<WavWriter> {
+    let mut writer = WavWriter {
+      file: try!(File::create(path)),
+      sample_rate: sample_rate as u32,
+      data_bytes: 0,
+      finished: false,
+    };
+    try!(writer.write_header());
+    Ok(writer)
+  }
+
+  fn write_header(&mut self) -> IoResult<()> {
+    let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+    try!(self.file.write(b"RIFF"));
+    try!(self.file.write_le_u32(HEADER_SIZE - 8 + self.data_bytes));
+    try!(self.file.write(b"WAVE"));
+    try!(self.file.write(b"fmt "));
+    try!(self.file.write_le_u32(16));             // fmt chunk size
+    try!(self.file.write_le_u16(1));              // PCM
+    try!(self.file.write_le_u16(CHANNELS));
+    try!(self.file.write_le_u32(self.sample_rate));
+    try!(self.file.write_le_u32(self.sample_rate * block_align as u32)); // byte rate
+    try!(self.file.write_le_u16(block_align));
+    try!(self.file.write_le_u16(BITS_PER_SAMPLE));
+    try!(self.file.write(b"data"));
+    try!(self.file.write_le_u32(self.data_bytes));
+    Ok(())
+  }
+
+  // Appends interleaved stereo samples with a single write
+  pub fn write_samples(&mut self, samples: &[i16]) -> IoResult<()> {
+    let mut bytes = Vec::with_capacity(samples.len() * 2);
+    for &s in samples.iter() {
+      bytes.push(s as u8);
+      bytes.push((s >> 8) as u8);
+    }
+    try!(self.file.write(bytes.as_slice()));
+    self.data_bytes += bytes.len() as u32;
+    Ok(())
+  }
+
+  // Writes the final data size into the header
+  pub fn finish(&mut self) -> IoResult<()> {
+    try!(self.file.seek(0, SeekSet));
+    try!(self.write_header());
+    try!(self.file.seek((HEADER_SIZE + self.data_bytes) as i64, SeekSet));
+    self.finished = true;
+    Ok(())
+  }
+}
+
+impl Drop for WavWriter {
+  // Keeps the file valid if recording stops without finish(), e.g. after a
+  // write error
+  fn drop(&mut self) {
+    if !self.finished {
+      let _ = self.finish();
+    }
+  }
+}
+
+
+//
+// Audio Recorder
+//
+
+// Records the sound unit's mixed output to a WAV file, independent of any
+// audio device.
+pub struct AudioRecorder {
+  resampler: audio::Resampler,
+  writer: WavWriter,
+}
+
+impl AudioRecorder {
+  pub fn create(path: &Path, sample_rate: uint) -> IoResult<AudioRecorder> {
+    Ok(AudioRecorder {
+      resampler: audio::Resampler::new(sample_rate),
+      writer: try!(WavWriter::create(path, sample_rate)),
+    })
+  }
+
+  pub fn push(&mut self, output: (i16, i16), cycles: u8) {
+    self.resampler.push(output, cycles);
+  }
+
+  pub fn flush(&mut self) -> IoResult<()> {
+    let samples = self.resampler.take_samples();
+    self.writer.write_samples(samples.as_slice())
+  }
+
+  // Writes the remaining samples, including the last partial one, and
+  // completes the file
+  pub fn finish(&mut self) -> IoResult<()> {
+    self.resampler.finish();
+    try!(self.flush());
+    self.writer.finish()
+  }
+}